@@ -26,36 +26,95 @@ pub(crate) fn get_rip() -> Addr {
     Addr(out)
 }
 
+#[derive(Clone)]
+#[repr(C)]
 pub(crate) struct Context {
     pub(crate) registers: [usize; 32],
 }
 
-pub(crate) fn capture_context() -> Context {
-    let mut context = Context { registers: [0; 32] };
-
-    unsafe {
-        asm!(
-            "mov [{regs}+1*8], rdx", // required special
-            "mov [{regs}+2*8], rcx", // required special
-            "mov [{regs}+3*8], rbx", // required callee-saved
-            "mov [{regs}+4*8], rsi", // required special
-            "mov [{regs}+5*8], rdi", // required special
-            "mov [{regs}+6*8], rbp", // required callee-saved
-            "mov [{regs}+7*8], rsp", // required callee-saved
-
-            "mov [{regs}+12*8], r12", // required callee-saved
-            "mov [{regs}+13*8], r13", // required callee-saved
-            "mov [{regs}+14*8], r14", // required callee-saved
-            "mov [{regs}+15*8], r15", // required callee-saved
-
-            "lea rax, [rip + 0]", // must use rip as a base register
-            "mov [{regs}+16*8], rax", // return address
-
-            out ("rax") _, // clobbers rax
-            regs = in(reg) &mut context.registers,
-            options(readonly),
-        );
+impl Context {
+    pub(crate) fn new() -> Self {
+        Context { registers: [0; 32] }
     }
+}
+
+/// Captures the registers of the calling function, as they are right after
+/// this function returns. The return address is stored as the instruction
+/// pointer, so the context describes the caller at its call site.
+///
+/// This has to be a naked function, as any frame of our own would be gone
+/// (and reused) by the time someone unwinds from the context.
+#[unsafe(naked)]
+pub(crate) unsafe extern "C" fn capture_context(context: *mut Context) {
+    core::arch::naked_asm!(
+        "mov [rdi+0*8], rax",
+        "mov [rdi+1*8], rdx", // required special
+        "mov [rdi+2*8], rcx", // required special
+        "mov [rdi+3*8], rbx", // required callee-saved
+        "mov [rdi+4*8], rsi", // required special
+        "mov [rdi+5*8], rdi", // required special
+        "mov [rdi+6*8], rbp", // required callee-saved
+        "lea rax, [rsp+8]",   // the stack pointer of the caller after we return
+        "mov [rdi+7*8], rax", // required callee-saved
+        "mov [rdi+8*8], r8",
+        "mov [rdi+9*8], r9",
+        "mov [rdi+10*8], r10",
+        "mov [rdi+11*8], r11",
+        "mov [rdi+12*8], r12", // required callee-saved
+        "mov [rdi+13*8], r13", // required callee-saved
+        "mov [rdi+14*8], r14", // required callee-saved
+        "mov [rdi+15*8], r15", // required callee-saved
+        "mov rax, [rsp]",
+        "mov [rdi+16*8], rax", // return address
+        "mov rax, [rdi+0*8]",
+        "ret",
+    )
+}
+
+/// Installs the context: restores all registers and jumps to its instruction
+/// pointer.
+///
+/// RAX and RDX are restored too, they are the `__builtin_eh_return_data_regno`
+/// registers that pass the exception pointer and selector to landing pads.
+///
+/// # Safety
+/// The context must describe a live frame on the current stack, with an
+/// instruction pointer that expects exactly these registers.
+pub(crate) unsafe fn restore_context(context: &Context) -> ! {
+    restore_context_raw(context)
+}
 
-    context
+#[unsafe(naked)]
+unsafe extern "C" fn restore_context_raw(context: *const Context) -> ! {
+    core::arch::naked_asm!(
+        // We still need RDI to read the context, so we place its new value and
+        // the new instruction pointer right below the new stack pointer, to
+        // pop them off after switching stacks.
+        "mov rax, [rdi+7*8]",
+        "sub rax, 16",
+        "mov rbx, [rdi+5*8]",
+        "mov [rax], rbx",
+        "mov rbx, [rdi+16*8]",
+        "mov [rax+8], rbx",
+        "mov rax, [rdi+0*8]",
+        "mov rdx, [rdi+1*8]",
+        "mov rcx, [rdi+2*8]",
+        "mov rbx, [rdi+3*8]",
+        "mov rsi, [rdi+4*8]",
+        "mov rbp, [rdi+6*8]",
+        "mov r8, [rdi+8*8]",
+        "mov r9, [rdi+9*8]",
+        "mov r10, [rdi+10*8]",
+        "mov r11, [rdi+11*8]",
+        "mov r12, [rdi+12*8]",
+        "mov r13, [rdi+13*8]",
+        "mov r14, [rdi+14*8]",
+        "mov r15, [rdi+15*8]",
+        // Only switch the stack at the very end, our own frames (including the
+        // context) are no longer protected after this.
+        "mov rsp, [rdi+7*8]",
+        "sub rsp, 16",
+        "pop rdi",
+        "ret",
+    )
 }
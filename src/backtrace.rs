@@ -0,0 +1,140 @@
+//! A safe backtrace API for Rust code, built on the same unwinding as
+//! exceptions.
+//!
+//! We don't allocate, so backtraces have a fixed capacity of
+//! [`Backtrace::MAX_FRAMES`] frames.
+
+use core::{fmt, slice};
+
+use crate::{
+    arch,
+    dwarf::parse::arch::{REG_STACK_POINTER, RETURN_ADDRESS},
+};
+
+/// A stack frame of a [`Backtrace`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    /// The instruction pointer. This is the return address into the frame, so
+    /// it points to the instruction after the call.
+    pub ip: usize,
+    /// The canonical frame address, the value of the stack pointer in the
+    /// caller before the call. 0 if the caller could not be unwound.
+    pub cfa: usize,
+    /// The stack pointer of the frame.
+    pub sp: usize,
+    /// The start address of the function of the frame.
+    pub symbol_address: usize,
+}
+
+/// The stack frames of the current thread at some point.
+#[derive(Clone)]
+pub struct Backtrace {
+    frames: [Frame; Backtrace::MAX_FRAMES],
+    len: usize,
+    truncated: bool,
+}
+
+impl Backtrace {
+    /// The maximum number of frames a backtrace holds. Outer frames are
+    /// dropped.
+    pub const MAX_FRAMES: usize = 128;
+
+    /// Captures a backtrace of the current thread. The first frame is the
+    /// caller of this function.
+    #[inline(never)]
+    pub fn capture() -> Self {
+        let _span = debug_span!("Backtrace::capture").entered();
+
+        let mut backtrace = Backtrace {
+            frames: [Frame {
+                ip: 0,
+                cfa: 0,
+                sp: 0,
+                symbol_address: 0,
+            }; Backtrace::MAX_FRAMES],
+            len: 0,
+            truncated: false,
+        };
+
+        let mut regs = arch::Context::new();
+        // SAFETY: The context is large enough for all registers.
+        unsafe { arch::capture_context(&mut regs) };
+
+        // The backtrace simply ends at the first frame we fail to unwind.
+        let mut next = crate::caller_of_captured(regs).ok().flatten();
+        while let Some(ctx) = next {
+            if backtrace.len == Backtrace::MAX_FRAMES {
+                backtrace.truncated = true;
+                break;
+            }
+
+            let caller_regs = crate::caller_regs(&ctx).ok();
+            backtrace.frames[backtrace.len] = Frame {
+                ip: ctx.regs.registers[RETURN_ADDRESS],
+                cfa: caller_regs
+                    .as_ref()
+                    .map_or(0, |regs| regs.registers[REG_STACK_POINTER]),
+                sp: ctx.regs.registers[REG_STACK_POINTER],
+                symbol_address: ctx.fde.initial_location,
+            };
+            backtrace.len += 1;
+
+            next = caller_regs.and_then(|regs| {
+                crate::frame(regs, ctx.fde.cie.is_signal_frame())
+                    .ok()
+                    .flatten()
+            });
+        }
+
+        backtrace
+    }
+
+    /// The captured frames, innermost first.
+    pub fn frames(&self) -> Frames<'_> {
+        Frames(self.frames[..self.len].iter())
+    }
+
+    /// Whether the stack had more than [`Backtrace::MAX_FRAMES`] frames.
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+}
+
+impl fmt::Debug for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.frames()).finish()
+    }
+}
+
+impl<'a> IntoIterator for &'a Backtrace {
+    type Item = Frame;
+    type IntoIter = Frames<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.frames()
+    }
+}
+
+/// An iterator over the frames of a [`Backtrace`].
+#[derive(Debug, Clone)]
+pub struct Frames<'a>(slice::Iter<'a, Frame>);
+
+impl Iterator for Frames<'_> {
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Frame> {
+        self.0.next().copied()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+
+impl DoubleEndedIterator for Frames<'_> {
+    fn next_back(&mut self) -> Option<Frame> {
+        self.0.next_back().copied()
+    }
+}
+
+impl ExactSizeIterator for Frames<'_> {}
@@ -10,14 +10,30 @@
 //! binary using the GNU extension (`_dl_find_object`)[https://www.gnu.org/software/libc/manual/html_node/Dynamic-Linker-Introspection.html].
 //! then, we parse that as beautiful DWARF call frame information, as god (or
 //! rather, the x86-64 psABI) intended.
+//!
+//! `_dl_find_object` only exists since glibc 2.35. without the `dl-find-object`
+//! feature, we walk the program headers of all loaded objects with
+//! `dl_iterate_phdr` instead, which works everywhere but is slower.
+//!
+//! if the dynamic linker doesn't know anything (static executables may not
+//! have one), we look at the program headers of the executable itself, which
+//! the kernel tells us about in the auxiliary vector.
 
 #![allow(non_camel_case_types)]
 
 use core::ffi;
 
-use super::parse::Encoding;
-use crate::{dwarf::parse::read_encoded, stdext::with_last_os_error_str, Addr};
+use super::parse::{
+    read_bytes, read_encoded, Bases, Cursor, EhFrame, Encoding, Error, ParsedFde, Result,
+};
+#[cfg(feature = "dl-find-object")]
+use crate::stdext::with_last_os_error_str;
+use crate::Addr;
 
+#[cfg(test)]
+mod tests;
+
+#[cfg(feature = "dl-find-object")]
 #[repr(C)]
 struct dl_find_object {
     dlfo_flags: ffi::c_ulonglong,
@@ -29,6 +45,7 @@ struct dl_find_object {
     dlfo_eh_frame: *const ffi::c_void,
 }
 
+#[cfg(feature = "dl-find-object")]
 extern "C" {
     fn _dl_find_object(address: *const ffi::c_void, result: *mut dl_find_object) -> ffi::c_int;
 }
@@ -43,11 +60,75 @@ struct EhFrameHeader {
     eh_frame_ptr_enc: Encoding,
     fde_count_enc: Encoding,
     table_enc: Encoding,
-    rest: (),
 }
 
+/// The `.eh_frame_hdr` of a loaded object, and where the mapped memory after
+/// it ends. Its `.eh_frame` is somewhere in between.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct LoadedObject {
+    eh_frame_hdr: *const EhFrameHeader,
+    /// The end of the `PT_LOAD` segment containing `.eh_frame_hdr` if we know
+    /// the program headers, otherwise the end of the whole object.
+    end: usize,
+}
+
+#[cfg(feature = "dl-find-object")]
+fn loaded_object(addr: Addr) -> Option<LoadedObject> {
+    dl_find_object_eh_frame_hdr(addr).or_else(|| auxv_eh_frame_hdr(addr))
+}
+
+#[cfg(not(feature = "dl-find-object"))]
+fn loaded_object(addr: Addr) -> Option<LoadedObject> {
+    dl_iterate_phdr_eh_frame_hdr(addr).or_else(|| auxv_eh_frame_hdr(addr))
+}
+
+/// Looks for the `PT_GNU_EH_FRAME` segment in the program headers of an
+/// object loaded at `bias`. Returns `None` if no `PT_LOAD` segment contains
+/// `addr`, and `Some(None)` if the object has no `.eh_frame_hdr`.
+///
+/// Linkers put `.eh_frame` in the same segment as `.eh_frame_hdr`, so we
+/// only look until the end of that segment. Anything after it may be a gap
+/// that isn't mapped.
+fn find_eh_frame_hdr_in_phdrs(
+    phdrs: &[libc::Elf64_Phdr],
+    bias: usize,
+    addr: usize,
+) -> Option<Option<LoadedObject>> {
+    let segments = || {
+        phdrs
+            .iter()
+            .filter(|phdr| phdr.p_type == libc::PT_LOAD)
+            .map(|phdr| {
+                let start = bias.wrapping_add(phdr.p_vaddr as usize);
+                start..(start + phdr.p_memsz as usize)
+            })
+    };
+    if !segments().any(|segment| segment.contains(&addr)) {
+        return None;
+    }
+
+    Some(
+        phdrs
+            .iter()
+            .find(|phdr| phdr.p_type == libc::PT_GNU_EH_FRAME)
+            .and_then(|phdr| {
+                let eh_frame_hdr = bias.wrapping_add(phdr.p_vaddr as usize);
+                let Some(segment) = segments().find(|segment| segment.contains(&eh_frame_hdr))
+                else {
+                    trace!("eh_frame_hdr at {eh_frame_hdr:x} is not in a PT_LOAD segment");
+                    return None;
+                };
+                Some(LoadedObject {
+                    eh_frame_hdr: core::ptr::with_exposed_provenance(eh_frame_hdr),
+                    end: segment.end,
+                })
+            }),
+    )
+}
+
+#[cfg(feature = "dl-find-object")]
 #[instrument]
-fn eh_frame_hdr_ptr(addr: Addr) -> Option<*const EhFrameHeader> {
+fn dl_find_object_eh_frame_hdr(addr: Addr) -> Option<LoadedObject> {
     unsafe {
         let mut out = core::mem::zeroed();
         let ret = _dl_find_object(addr.voidptr(), &mut out);
@@ -74,151 +155,241 @@ fn eh_frame_hdr_ptr(addr: Addr) -> Option<*const EhFrameHeader> {
             return None;
         }
 
-        Some(out.dlfo_eh_frame.cast::<EhFrameHeader>())
+        Some(LoadedObject {
+            eh_frame_hdr: out.dlfo_eh_frame.cast(),
+            end: out.dlfo_map_end.addr(),
+        })
     }
 }
 
+/// Finds the `PT_GNU_EH_FRAME` segment of the object that has a `PT_LOAD`
+/// segment containing `addr`.
 #[instrument]
-pub(crate) fn eh_frame(addr: Addr) -> Option<*const u8> {
-    unsafe {
-        let ptr = eh_frame_hdr_ptr(addr)?;
-        let header = ptr.read();
-
-        // ptr now points to `eh_frame_ptr`.
-        let ptr = ptr.cast::<u8>().add(4);
+fn dl_iterate_phdr_eh_frame_hdr(addr: Addr) -> Option<LoadedObject> {
+    struct Search {
+        addr: usize,
+        object: Option<LoadedObject>,
+    }
 
-        if header.version != 1 {
-            trace!("eh_frame_hdr version is not 1");
-            return None;
+    unsafe extern "C" fn callback(
+        info: *mut libc::dl_phdr_info,
+        _size: libc::size_t,
+        data: *mut ffi::c_void,
+    ) -> ffi::c_int {
+        let search = &mut *data.cast::<Search>();
+        let info = &*info;
+        let phdrs = core::slice::from_raw_parts(info.dlpi_phdr, info.dlpi_phnum as usize);
+
+        match find_eh_frame_hdr_in_phdrs(phdrs, info.dlpi_addr as usize, search.addr) {
+            Some(object) => {
+                trace!("found object containing address: {object:?}");
+                search.object = object;
+                // Stop iterating, even if the object has no `.eh_frame_hdr`.
+                1
+            }
+            None => 0,
         }
+    }
 
-        trace!("eh_frame_hdr: {:?}", header);
-
-        let (read_size, eh_frame_ptr) = read_encoded(ptr, header.eh_frame_ptr_enc, None);
-        let ptr = ptr.add(read_size);
-        let (_read_size, fde_count) = read_encoded(ptr, header.fde_count_enc, None);
+    let mut search = Search {
+        addr: addr.addr(),
+        object: None,
+    };
+    unsafe {
+        libc::dl_iterate_phdr(Some(callback), (&raw mut search).cast::<ffi::c_void>());
+    }
+    search.object
+}
 
-        trace!("eh_frame: {eh_frame_ptr:?}");
-        trace!("fde_count: {fde_count:?}");
+/// Finds the `PT_GNU_EH_FRAME` segment of the executable itself, if it
+/// contains `addr`. This works without any help from a dynamic linker, so it
+/// also covers static executables.
+#[instrument]
+fn auxv_eh_frame_hdr(addr: Addr) -> Option<LoadedObject> {
+    unsafe {
+        let phdr = libc::getauxval(libc::AT_PHDR) as usize;
+        let phnum = libc::getauxval(libc::AT_PHNUM) as usize;
+        if phdr == 0 {
+            trace!("no program headers in the auxiliary vector");
+            return None;
+        }
 
-        trace!(
-            "eh_frame start: {:x?}",
-            core::slice::from_raw_parts(eh_frame_ptr as *const u8, 15)
+        let phdrs = core::slice::from_raw_parts(
+            core::ptr::with_exposed_provenance::<libc::Elf64_Phdr>(phdr),
+            phnum,
         );
 
-        Some(eh_frame_ptr as *const u8)
+        // The load bias is where the program headers are minus where they
+        // wanted to be. Without `PT_PHDR`, the executable can't be
+        // position-independent.
+        let bias = phdrs
+            .iter()
+            .find(|phdr| phdr.p_type == libc::PT_PHDR)
+            .map_or(0, |program_headers| {
+                phdr.wrapping_sub(program_headers.p_vaddr as usize)
+            });
+
+        find_eh_frame_hdr_in_phdrs(phdrs, bias, addr.addr()).flatten()
     }
 }
 
+/// Finds the FDE covering `addr`, and the base addresses of its module.
+/// Code that was loaded by the dynamic linker is looked up first, then code
+/// that registered its frame information at runtime.
+///
+/// Returns `Ok(None)` if no FDE covers `addr`, and an error if the frame
+/// information we found is invalid.
 #[instrument]
-pub(crate) fn frame_info(addr: Addr) -> Option<()> {
+pub(crate) fn frame_info(addr: Addr) -> Result<Option<ParsedFde<'static>>> {
     let symbol = crate::identify::identify(addr.addr());
 
     debug!("getting frame information of {symbol:?}");
 
-    unsafe {
-        let header_ptr = eh_frame_hdr_ptr(addr)?;
-        let eh_frame_header_addr = header_ptr.addr();
-        let header = header_ptr.read();
-
-        let ptr = (&raw const (*header_ptr).rest).cast::<u8>();
-        let (eh_frame_ptr_size, eh_frame_ptr) = read_encoded(ptr, header.eh_frame_ptr_enc, None);
-        let ptr = ptr.add(eh_frame_ptr_size);
-
-        let (fde_count_size, fde_count) =
-            read_encoded(ptr, header.fde_count_enc, Some(eh_frame_ptr));
-
-        trace!(?header.table_enc);
+    match loaded_frame_info(addr)? {
+        Some(frame_info) => Ok(Some(frame_info)),
+        None => Ok(super::registry::frame_info(addr.addr())),
+    }
+}
 
-        let table_ptr = ptr.add(fde_count_size);
+/// Finds the FDE covering `addr` in the objects loaded by the dynamic linker.
+fn loaded_frame_info(addr: Addr) -> Result<Option<ParsedFde<'static>>> {
+    let Some(object) = loaded_object(addr) else {
+        return Ok(None);
+    };
+    let eh_frame_header_addr = object.eh_frame_hdr.addr();
+    // SAFETY: The segment is loaded, so everything up to its end is mapped.
+    // `_dl_find_object` only tells us where the whole object ends, which is
+    // only mapped all the way if the dynamic linker didn't leave `PROT_NONE`
+    // gaps between the segments. We rely on `.eh_frame_hdr` and `.eh_frame`
+    // being valid, so that we never read that far.
+    let eh_frame_hdr = unsafe {
+        core::slice::from_raw_parts(
+            object.eh_frame_hdr.cast::<u8>(),
+            object.end.saturating_sub(eh_frame_header_addr),
+        )
+    };
+
+    let data = &mut Cursor(eh_frame_hdr);
+    read_bytes(data, size_of::<EhFrameHeader>())?;
+    // SAFETY: We just checked that the header is in bounds.
+    let header = unsafe { object.eh_frame_hdr.read() };
+
+    if header.version != 1 {
+        return Err(Error::UnsupportedVersion(header.version));
+    }
+    if header.eh_frame_ptr_enc.is_omit() {
+        trace!("eh_frame_hdr does not point to .eh_frame");
+        return Ok(None);
+    }
 
-        /*
-        let mut walk_table_ptr = table_ptr;
-        for i in 0..fde_count {
-            let (read, initial_loc) =
-                read_encoded(walk_table_ptr, header.table_enc, Some(eh_frame_header_addr));
-            walk_table_ptr = walk_table_ptr.add(read);
-            let (read, address) =
-                read_encoded(walk_table_ptr, header.table_enc, Some(eh_frame_header_addr));
-            walk_table_ptr = walk_table_ptr.add(read);
+    // Data-relative values in `.eh_frame_hdr` are relative to its start.
+    let bases = Bases {
+        data: eh_frame_header_addr,
+        ..Bases::default()
+    };
+
+    // The section is loaded, so the address of a value is where we read it.
+    let eh_frame_ptr = read_encoded(
+        data,
+        data.0.as_ptr().addr(),
+        header.eh_frame_ptr_enc,
+        &bases,
+    )?;
+    if eh_frame_ptr > object.end {
+        return Err(Error::Truncated);
+    }
+    // SAFETY: `.eh_frame` is in the same segment as `.eh_frame_hdr`, see
+    // above.
+    let eh_frame = EhFrame::in_process(unsafe {
+        core::slice::from_raw_parts(
+            core::ptr::with_exposed_provenance::<u8>(eh_frame_ptr),
+            object.end - eh_frame_ptr,
+        )
+    });
+
+    trace!(?header.table_enc);
+
+    // Without a search table, we have to look at every FDE.
+    if header.fde_count_enc.is_omit() || header.table_enc.is_omit() {
+        trace!("eh_frame_hdr has no search table, scanning .eh_frame");
+        return eh_frame.find_fde(addr.addr());
+    }
 
-            trace!(idx = ?i, "eh_frame_hdr table initial_loc={initial_loc:x} address={address:x}");
-        }
-        */
+    let fde_count = read_encoded(data, data.0.as_ptr().addr(), header.fde_count_enc, &bases)?;
+    if fde_count == 0 {
+        trace!("eh_frame_hdr search table is empty");
+        return Ok(None);
+    }
 
-        let table_half_entry_size = header.table_enc.size();
+    // Every entry is the start of a function and the address of its FDE.
+    let table = data.0;
+    let table_half_entry_size = header.table_enc.size()?;
+    let table_entry_size = table_half_entry_size * 2;
+    if table.len() / table_entry_size < fde_count {
+        return Err(Error::Truncated);
+    }
+    let read_table = |offset: usize| {
+        let entry = &table[offset..];
+        read_encoded(
+            &mut Cursor(entry),
+            entry.as_ptr().addr(),
+            header.table_enc,
+            &bases,
+        )
+    };
+
+    // Find the last entry that starts at or before `addr`.
+    let mut base = 0;
+    let mut len = fde_count;
+    while len > 1 {
+        let mid = base + len / 2;
+        let value = read_table(mid * table_entry_size)?;
+
+        debug!(
+            ?base,
+            ?len,
+            ?mid,
+            "binary searching for {addr:?}: {value:x}"
+        );
 
-        let mut base = 0;
-        let mut len = fde_count;
-        let found_fde;
-        loop {
-            if len == 1 {
-                found_fde = Some(base);
+        match addr.addr().cmp(&value) {
+            core::cmp::Ordering::Less => {
+                len = mid - base;
+            }
+            core::cmp::Ordering::Equal => {
+                base = mid;
                 break;
             }
-
-            let mid = base + len / 2;
-            let mid_ptr = table_ptr.byte_add(mid * table_half_entry_size * 2);
-
-            let (_, value) = read_encoded(mid_ptr, header.table_enc, Some(eh_frame_header_addr));
-
-            debug!(
-                ?base,
-                ?len,
-                ?mid,
-                "binary searching for {addr:?}: {value:x}"
-            );
-
-            match addr.addr().cmp(&value) {
-                core::cmp::Ordering::Less => {
-                    len = mid - base;
-                }
-                core::cmp::Ordering::Equal => {
-                    found_fde = Some(mid);
-                    break;
-                }
-                core::cmp::Ordering::Greater => {
-                    len = len - (mid - base);
-                    base = mid;
-                }
+            core::cmp::Ordering::Greater => {
+                len -= mid - base;
+                base = mid;
             }
         }
+    }
 
-        debug!("found FDE idx in binary search {found_fde:?}");
-
-        let fde_table_ptr = table_ptr.byte_add(found_fde.unwrap() * table_half_entry_size * 2);
-        let (_, fde_address) = read_encoded(
-            fde_table_ptr.byte_add(table_half_entry_size),
-            header.table_enc,
-            Some(eh_frame_header_addr),
-        );
-
-        trace!("found FDE at address {fde_address:x}");
-
-        let fde_ptr = core::ptr::with_exposed_provenance::<u8>(fde_address);
-
-        fde_ptr.read_volatile();
+    debug!("found FDE idx in binary search {base}");
 
-        trace!("ptr is valid");
+    let fde_address = read_table(base * table_entry_size + table_half_entry_size)?;
 
-        trace!(
-            "FDE offset to .eh_frame: {:x}",
-            fde_ptr.addr() - (eh_frame_ptr)
-        );
+    trace!("found FDE at address {fde_address:x}");
 
-        let fde = crate::dwarf::parse::parse_fde_from_ptr(fde_ptr, eh_frame_ptr).unwrap();
+    let fde_offset = fde_address
+        .checked_sub(eh_frame_ptr)
+        .ok_or(Error::InvalidFdePointer)?;
+    trace!("FDE offset to .eh_frame: {fde_offset:x}");
 
-        let fde_base_offset = addr.addr() - fde.initial_location;
+    let fde = eh_frame.fde_at(fde_offset)?;
 
-        trace!("fde initial location {:x}, address offset of {fde_base_offset}", fde.initial_location);
+    trace!(
+        "fde initial location {:x}, address range {:x}",
+        fde.initial_location,
+        fde.address_range
+    );
 
-        crate::dwarf::parse::process_instructions_cfa(
-            &fde.cie,
-            fde.initial_instructions,
-            fde.instructions,
-            fde_base_offset,
-        );
-
-        todo!()
+    if !fde.covers(addr.addr()) {
+        trace!("FDE does not cover {addr:?}");
+        return Ok(None);
     }
+
+    Ok(Some(fde))
 }
@@ -0,0 +1,79 @@
+use crate::Addr;
+
+#[test]
+fn dl_iterate_phdr_finds_eh_frame_hdr() {
+    let addr = Addr((dl_iterate_phdr_finds_eh_frame_hdr as *const ()).cast());
+
+    let object = super::dl_iterate_phdr_eh_frame_hdr(addr).unwrap();
+    assert_eq!(unsafe { object.eh_frame_hdr.read() }.version, 1);
+    assert!(object.eh_frame_hdr.addr() < object.end);
+
+    #[cfg(feature = "dl-find-object")]
+    assert_eq!(
+        super::dl_find_object_eh_frame_hdr(addr).map(|object| object.eh_frame_hdr),
+        Some(object.eh_frame_hdr)
+    );
+
+    assert!(super::dl_iterate_phdr_eh_frame_hdr(Addr(core::ptr::null())).is_none());
+}
+
+#[test]
+fn auxv_finds_eh_frame_hdr() {
+    // The test binary is the executable, so its program headers are in the
+    // auxiliary vector.
+    let addr = Addr((auxv_finds_eh_frame_hdr as *const ()).cast());
+    assert_eq!(
+        super::auxv_eh_frame_hdr(addr),
+        super::dl_iterate_phdr_eh_frame_hdr(addr)
+    );
+    assert!(super::auxv_eh_frame_hdr(addr).is_some());
+    assert!(super::auxv_eh_frame_hdr(Addr(core::ptr::null())).is_none());
+}
+
+fn phdr(p_type: u32, p_vaddr: u64, p_memsz: u64) -> libc::Elf64_Phdr {
+    libc::Elf64_Phdr {
+        p_type,
+        p_flags: 0,
+        p_offset: 0,
+        p_vaddr,
+        p_paddr: p_vaddr,
+        p_filesz: p_memsz,
+        p_memsz,
+        p_align: 0x1000,
+    }
+}
+
+#[test]
+fn eh_frame_hdr_segment_bounds_object() {
+    // Code, then a gap, then `.eh_frame_hdr` and `.eh_frame`, then another gap
+    // before the data.
+    let phdrs = [
+        phdr(libc::PT_LOAD, 0x1000, 0x1000),
+        phdr(libc::PT_LOAD, 0x4000, 0x800),
+        phdr(libc::PT_GNU_EH_FRAME, 0x4100, 0x40),
+        phdr(libc::PT_LOAD, 0x8000, 0x1000),
+    ];
+    let bias = 0x10_0000;
+
+    let object = super::find_eh_frame_hdr_in_phdrs(&phdrs, bias, bias + 0x1800)
+        .unwrap()
+        .unwrap();
+    assert_eq!(object.eh_frame_hdr.addr(), bias + 0x4100);
+    assert_eq!(object.end, bias + 0x4800);
+
+    // Not in any segment, even though it's in between them.
+    assert_eq!(
+        super::find_eh_frame_hdr_in_phdrs(&phdrs, bias, bias + 0x3000),
+        None
+    );
+    // The object has no `.eh_frame_hdr` we can read.
+    assert_eq!(
+        super::find_eh_frame_hdr_in_phdrs(&phdrs[..1], bias, bias + 0x1800),
+        Some(None)
+    );
+    let outside = [phdrs[0], phdr(libc::PT_GNU_EH_FRAME, 0x3000, 0x40)];
+    assert_eq!(
+        super::find_eh_frame_hdr_in_phdrs(&outside, bias, bias + 0x1800),
+        Some(None)
+    );
+}
@@ -0,0 +1,318 @@
+//! Evaluation of DWARF expressions.
+//!
+//! Source: https://dwarfstd.org/doc/DWARF5.pdf §2.5 DWARF Expressions
+//!
+//! DWARF expressions are programs for a small stack machine. Call frame
+//! information uses them when a simple register + offset rule isn't enough,
+//! for example for PLT entries and signal trampolines. Only the operations
+//! that are allowed in call frame information (§6.4.2) are supported.
+#![allow(non_upper_case_globals)]
+
+#[cfg(test)]
+mod tests;
+
+use super::parse::{read_bytes, read_ileb128, read_uleb128, Cursor, Error, Expr, Result};
+use crate::arch::Context;
+
+/// Reads memory of the process that is being unwound.
+pub(crate) trait Memory {
+    /// Reads `size` bytes (1, 2, 4 or 8) at `addr`, zero-extended.
+    fn read(&self, addr: usize, size: usize) -> Result<usize>;
+}
+
+/// Reads the memory of our own process.
+pub(crate) struct ProcessMemory(());
+
+impl ProcessMemory {
+    /// # Safety
+    /// Every address read through this must be valid for reads. When unwinding
+    /// our own stack, this means trusting the call frame information.
+    pub(crate) unsafe fn new() -> Self {
+        Self(())
+    }
+}
+
+impl Memory for ProcessMemory {
+    fn read(&self, addr: usize, size: usize) -> Result<usize> {
+        let ptr = core::ptr::with_exposed_provenance::<u8>(addr);
+        // SAFETY: The creator of ProcessMemory promised that addresses are valid.
+        unsafe {
+            match size {
+                1 => Ok(ptr.read() as usize),
+                2 => Ok(ptr.cast::<u16>().read_unaligned() as usize),
+                4 => Ok(ptr.cast::<u32>().read_unaligned() as usize),
+                8 => Ok(ptr.cast::<u64>().read_unaligned() as usize),
+                _ => Err(Error::InvalidReadSize(size)),
+            }
+        }
+    }
+}
+
+const DW_OP_addr: u8 = 0x03;
+const DW_OP_deref: u8 = 0x06;
+const DW_OP_const1u: u8 = 0x08;
+const DW_OP_const1s: u8 = 0x09;
+const DW_OP_const2u: u8 = 0x0a;
+const DW_OP_const2s: u8 = 0x0b;
+const DW_OP_const4u: u8 = 0x0c;
+const DW_OP_const4s: u8 = 0x0d;
+const DW_OP_const8u: u8 = 0x0e;
+const DW_OP_const8s: u8 = 0x0f;
+const DW_OP_constu: u8 = 0x10;
+const DW_OP_consts: u8 = 0x11;
+const DW_OP_dup: u8 = 0x12;
+const DW_OP_drop: u8 = 0x13;
+const DW_OP_over: u8 = 0x14;
+const DW_OP_pick: u8 = 0x15;
+const DW_OP_swap: u8 = 0x16;
+const DW_OP_rot: u8 = 0x17;
+const DW_OP_abs: u8 = 0x19;
+const DW_OP_and: u8 = 0x1a;
+const DW_OP_div: u8 = 0x1b;
+const DW_OP_minus: u8 = 0x1c;
+const DW_OP_mod: u8 = 0x1d;
+const DW_OP_mul: u8 = 0x1e;
+const DW_OP_neg: u8 = 0x1f;
+const DW_OP_not: u8 = 0x20;
+const DW_OP_or: u8 = 0x21;
+const DW_OP_plus: u8 = 0x22;
+const DW_OP_plus_uconst: u8 = 0x23;
+const DW_OP_shl: u8 = 0x24;
+const DW_OP_shr: u8 = 0x25;
+const DW_OP_shra: u8 = 0x26;
+const DW_OP_xor: u8 = 0x27;
+const DW_OP_bra: u8 = 0x28;
+const DW_OP_eq: u8 = 0x29;
+const DW_OP_ge: u8 = 0x2a;
+const DW_OP_gt: u8 = 0x2b;
+const DW_OP_le: u8 = 0x2c;
+const DW_OP_lt: u8 = 0x2d;
+const DW_OP_ne: u8 = 0x2e;
+const DW_OP_skip: u8 = 0x2f;
+const DW_OP_lit0: u8 = 0x30;
+const DW_OP_lit31: u8 = 0x4f;
+const DW_OP_breg0: u8 = 0x70;
+const DW_OP_breg31: u8 = 0x8f;
+const DW_OP_bregx: u8 = 0x92;
+const DW_OP_deref_size: u8 = 0x94;
+const DW_OP_nop: u8 = 0x96;
+
+/// Expressions can loop with `DW_OP_skip` and `DW_OP_bra`, so we give up after
+/// this many operations instead of hanging on bad call frame information.
+const MAX_OPERATIONS: usize = 10_000;
+
+/// The maximum depth of the evaluation stack. We never allocate while
+/// unwinding, and the expressions in call frame information are tiny.
+const STACK_SIZE: usize = 64;
+
+struct Stack {
+    values: [usize; STACK_SIZE],
+    len: usize,
+}
+
+impl Stack {
+    fn push(&mut self, value: usize) -> Result<()> {
+        let slot = self
+            .values
+            .get_mut(self.len)
+            .ok_or(Error::ExpressionStackOverflow)?;
+        *slot = value;
+        self.len += 1;
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<usize> {
+        if self.len == 0 {
+            return Err(Error::ExpressionStackUnderflow);
+        }
+        self.len -= 1;
+        Ok(self.values[self.len])
+    }
+
+    /// Returns the entry `idx` entries below the top of the stack.
+    fn peek(&self, idx: usize) -> Result<usize> {
+        if idx >= self.len {
+            return Err(Error::ExpressionStackUnderflow);
+        }
+        Ok(self.values[self.len - 1 - idx])
+    }
+}
+
+fn read_int<const N: usize>(ops: &mut Cursor<'_>) -> Result<[u8; N]> {
+    let mut int = [0; N];
+    int.copy_from_slice(read_bytes(ops, N)?);
+    Ok(int)
+}
+
+/// Evaluates the expression and returns the value on top of the stack.
+///
+/// Registers are read from `regs`, memory through `memory`. If `initial` is
+/// set, it's pushed on the stack before evaluation starts (the CFA for
+/// register rules).
+pub(crate) fn evaluate(
+    expr: Expr<'_>,
+    regs: &Context,
+    memory: &impl Memory,
+    initial: Option<usize>,
+) -> Result<usize> {
+    trace!("evaluating DWARF expression {:x?}", expr.0);
+
+    let mut stack = Stack {
+        values: [0; STACK_SIZE],
+        len: 0,
+    };
+    if let Some(initial) = initial {
+        stack.push(initial)?;
+    }
+
+    let register = |register: usize| {
+        regs.registers
+            .get(register)
+            .copied()
+            .ok_or(Error::InvalidRegister(register))
+    };
+
+    let mut ops = Cursor(expr.0);
+    let mut executed = 0;
+    while let Some((&op, rest)) = ops.0.split_first() {
+        ops.0 = rest;
+
+        executed += 1;
+        if executed > MAX_OPERATIONS {
+            return Err(Error::ExpressionTooLong);
+        }
+
+        match op {
+            DW_OP_addr => stack.push(u64::from_le_bytes(read_int(&mut ops)?) as usize)?,
+            DW_OP_deref => {
+                let addr = stack.pop()?;
+                stack.push(memory.read(addr, size_of::<usize>())?)?;
+            }
+            DW_OP_deref_size => {
+                let size = read_int::<1>(&mut ops)?[0];
+                let addr = stack.pop()?;
+                stack.push(memory.read(addr, size as usize)?)?;
+            }
+            DW_OP_const1u => stack.push(u8::from_le_bytes(read_int(&mut ops)?) as usize)?,
+            DW_OP_const1s => stack.push(i8::from_le_bytes(read_int(&mut ops)?) as usize)?,
+            DW_OP_const2u => stack.push(u16::from_le_bytes(read_int(&mut ops)?) as usize)?,
+            DW_OP_const2s => stack.push(i16::from_le_bytes(read_int(&mut ops)?) as usize)?,
+            DW_OP_const4u => stack.push(u32::from_le_bytes(read_int(&mut ops)?) as usize)?,
+            DW_OP_const4s => stack.push(i32::from_le_bytes(read_int(&mut ops)?) as usize)?,
+            DW_OP_const8u => stack.push(u64::from_le_bytes(read_int(&mut ops)?) as usize)?,
+            DW_OP_const8s => stack.push(i64::from_le_bytes(read_int(&mut ops)?) as usize)?,
+            DW_OP_constu => stack.push(read_uleb128(&mut ops)?)?,
+            DW_OP_consts => stack.push(read_ileb128(&mut ops)? as usize)?,
+            DW_OP_lit0..=DW_OP_lit31 => stack.push((op - DW_OP_lit0) as usize)?,
+            DW_OP_breg0..=DW_OP_breg31 => {
+                let offset = read_ileb128(&mut ops)?;
+                let value = register((op - DW_OP_breg0) as usize)?;
+                stack.push(value.wrapping_add_signed(offset))?;
+            }
+            DW_OP_bregx => {
+                let reg = read_uleb128(&mut ops)?;
+                let offset = read_ileb128(&mut ops)?;
+                stack.push(register(reg)?.wrapping_add_signed(offset))?;
+            }
+            DW_OP_dup => stack.push(stack.peek(0)?)?,
+            DW_OP_drop => {
+                stack.pop()?;
+            }
+            DW_OP_over => stack.push(stack.peek(1)?)?,
+            DW_OP_pick => {
+                let idx = read_int::<1>(&mut ops)?[0];
+                stack.push(stack.peek(idx as usize)?)?;
+            }
+            DW_OP_swap => {
+                let a = stack.pop()?;
+                let b = stack.pop()?;
+                stack.push(a)?;
+                stack.push(b)?;
+            }
+            DW_OP_rot => {
+                let a = stack.pop()?;
+                let b = stack.pop()?;
+                let c = stack.pop()?;
+                stack.push(a)?;
+                stack.push(c)?;
+                stack.push(b)?;
+            }
+            DW_OP_abs => {
+                let a = stack.pop()? as isize;
+                stack.push(a.unsigned_abs())?;
+            }
+            DW_OP_neg => {
+                let a = stack.pop()? as isize;
+                stack.push(a.wrapping_neg() as usize)?;
+            }
+            DW_OP_not => {
+                let a = stack.pop()?;
+                stack.push(!a)?;
+            }
+            DW_OP_plus_uconst => {
+                let a = stack.pop()?;
+                stack.push(a.wrapping_add(read_uleb128(&mut ops)?))?;
+            }
+            DW_OP_and | DW_OP_div | DW_OP_minus | DW_OP_mod | DW_OP_mul | DW_OP_or | DW_OP_plus
+            | DW_OP_shl | DW_OP_shr | DW_OP_shra | DW_OP_xor | DW_OP_eq | DW_OP_ge | DW_OP_gt
+            | DW_OP_le | DW_OP_lt | DW_OP_ne => {
+                // The top of the stack is the second operand.
+                let b = stack.pop()?;
+                let a = stack.pop()?;
+                let value = match op {
+                    DW_OP_and => a & b,
+                    DW_OP_div => {
+                        if b == 0 {
+                            return Err(Error::DivisionByZero);
+                        }
+                        (a as isize).wrapping_div(b as isize) as usize
+                    }
+                    DW_OP_minus => a.wrapping_sub(b),
+                    DW_OP_mod => {
+                        if b == 0 {
+                            return Err(Error::DivisionByZero);
+                        }
+                        a % b
+                    }
+                    DW_OP_mul => a.wrapping_mul(b),
+                    DW_OP_or => a | b,
+                    DW_OP_plus => a.wrapping_add(b),
+                    DW_OP_shl => a.checked_shl(b as u32).unwrap_or(0),
+                    DW_OP_shr => a.checked_shr(b as u32).unwrap_or(0),
+                    DW_OP_shra => (a as isize)
+                        .checked_shr(b as u32)
+                        .unwrap_or(if (a as isize) < 0 { -1 } else { 0 })
+                        as usize,
+                    DW_OP_xor => a ^ b,
+                    // Comparisons are signed.
+                    DW_OP_eq => (a == b) as usize,
+                    DW_OP_ge => ((a as isize) >= (b as isize)) as usize,
+                    DW_OP_gt => ((a as isize) > (b as isize)) as usize,
+                    DW_OP_le => ((a as isize) <= (b as isize)) as usize,
+                    DW_OP_lt => ((a as isize) < (b as isize)) as usize,
+                    DW_OP_ne => (a != b) as usize,
+                    _ => return Err(Error::UnknownOpcode(op)),
+                };
+                stack.push(value)?;
+            }
+            DW_OP_skip | DW_OP_bra => {
+                let offset = i16::from_le_bytes(read_int(&mut ops)?);
+                let jump = op == DW_OP_skip || stack.pop()? != 0;
+                if jump {
+                    let pos = expr.0.len() - ops.0.len();
+                    let target = pos
+                        .checked_add_signed(offset as isize)
+                        .filter(|&target| target <= expr.0.len())
+                        .ok_or(Error::InvalidJump)?;
+                    ops = Cursor(&expr.0[target..]);
+                }
+            }
+            DW_OP_nop => {}
+            _ => return Err(Error::UnknownOpcode(op)),
+        }
+    }
+
+    let result = stack.pop()?;
+    trace!("DWARF expression result: {result:x}");
+    Ok(result)
+}
@@ -0,0 +1,111 @@
+use crate::{
+    arch::Context,
+    dwarf::{
+        expr::{evaluate, Memory},
+        parse::{Error, Expr, Result},
+    },
+};
+
+/// Fake memory made of words, starting at `base`.
+struct Words<'a> {
+    base: usize,
+    words: &'a [usize],
+}
+
+impl Memory for Words<'_> {
+    fn read(&self, addr: usize, size: usize) -> Result<usize> {
+        assert_eq!(size, 8);
+        addr.checked_sub(self.base)
+            .and_then(|offset| self.words.get(offset / 8))
+            .copied()
+            .ok_or(Error::Truncated)
+    }
+}
+
+const NO_MEMORY: Words<'static> = Words {
+    base: 0,
+    words: &[],
+};
+
+fn eval(expr: &[u8], initial: Option<usize>) -> usize {
+    let mut regs = Context::new();
+    regs.registers[6] = 0x1000;
+    regs.registers[7] = 0x2000;
+    evaluate(Expr(expr), &regs, &NO_MEMORY, initial).unwrap()
+}
+
+#[test]
+fn constants_and_arithmetic() {
+    // DW_OP_lit5 DW_OP_const1u 10 DW_OP_plus
+    assert_eq!(eval(&[0x35, 0x08, 10, 0x22], None), 15);
+    // DW_OP_const1s -3 DW_OP_lit4 DW_OP_mul
+    assert_eq!(eval(&[0x09, 0xfd, 0x34, 0x1e], None), (-12_isize) as usize);
+    // DW_OP_lit10 DW_OP_lit3 DW_OP_minus DW_OP_plus_uconst 2
+    assert_eq!(eval(&[0x3a, 0x33, 0x1c, 0x23, 2], None), 9);
+    // DW_OP_consts -8 DW_OP_abs DW_OP_lit1 DW_OP_shl
+    assert_eq!(eval(&[0x11, 0x78, 0x19, 0x31, 0x24], None), 16);
+    // The initial value is on the stack: DW_OP_lit1 DW_OP_plus
+    assert_eq!(eval(&[0x31, 0x22], Some(41)), 42);
+}
+
+#[test]
+fn registers() {
+    // DW_OP_breg7 +8
+    assert_eq!(eval(&[0x77, 0x08], None), 0x2008);
+    // DW_OP_bregx RBP -16
+    assert_eq!(eval(&[0x92, 0x06, 0x70], None), 0x1000 - 16);
+}
+
+#[test]
+fn control_flow() {
+    // DW_OP_lit0 DW_OP_bra +1 DW_OP_lit1 DW_OP_lit2
+    // Doesn't branch, the stack is [1, 2].
+    assert_eq!(eval(&[0x30, 0x28, 1, 0, 0x31, 0x32], None), 2);
+    // DW_OP_lit7 DW_OP_lit1 DW_OP_bra +1 DW_OP_lit1
+    // Branches over the last literal.
+    assert_eq!(eval(&[0x37, 0x31, 0x28, 1, 0, 0x31], None), 7);
+    // DW_OP_lit3 DW_OP_skip +2 DW_OP_lit1 DW_OP_plus DW_OP_lit6
+    // Skips straight to the last literal.
+    assert_eq!(eval(&[0x33, 0x2f, 2, 0, 0x31, 0x22, 0x36], None), 6);
+    // DW_OP_lit3 DW_OP_lit4 DW_OP_lt
+    assert_eq!(eval(&[0x33, 0x34, 0x2d], None), 1);
+}
+
+#[test]
+fn deref() {
+    let memory = Words {
+        base: 0x2000,
+        words: &[0xaaaa, 0xbbbb],
+    };
+    let mut regs = Context::new();
+    regs.registers[7] = 0x2000;
+
+    // DW_OP_breg7 +8 DW_OP_deref: what a signal trampoline does.
+    let value = evaluate(Expr(&[0x77, 0x08, 0x06]), &regs, &memory, None).unwrap();
+    assert_eq!(value, 0xbbbb);
+}
+
+#[test]
+fn errors() {
+    let regs = Context::new();
+    // DW_OP_plus on an empty stack
+    assert_eq!(
+        evaluate(Expr(&[0x22]), &regs, &NO_MEMORY, None),
+        Err(Error::ExpressionStackUnderflow)
+    );
+    // DW_OP_skip -3 loops forever
+    assert_eq!(
+        evaluate(Expr(&[0x2f, 0xfd, 0xff]), &regs, &NO_MEMORY, None),
+        Err(Error::ExpressionTooLong)
+    );
+    // DW_OP_lit0 DW_OP_deref
+    assert_eq!(
+        evaluate(Expr(&[0x30, 0x06]), &regs, &NO_MEMORY, None),
+        Err(Error::Truncated)
+    );
+    // DW_OP_fbreg is not allowed in CFI
+    assert_eq!(
+        evaluate(Expr(&[0x91, 0x00]), &regs, &NO_MEMORY, None),
+        Err(Error::UnknownOpcode(0x91))
+    );
+}
@@ -6,9 +6,13 @@
 //! See https://dwarfstd.org/doc/DWARF5.pdf §6.4 for more information if more information is desired.
 //! Note that https://refspecs.linuxbase.org/LSB_5.0.0/LSB-Core-generic/LSB-Core-generic/ehframechpt.html
 //! contains more details on the precise format, which is slightly different
-//! from .debug_frame from DWARF.
+//! from .debug_frame from DWARF. We can parse both, but only .eh_frame is
+//! loaded at runtime, so .debug_frame is for offline tooling.
 
 mod divination;
+pub(crate) mod expr;
 pub(crate) mod parse;
+pub mod registry;
 
-pub(crate) use divination::{eh_frame, frame_info};
+pub(crate) use divination::frame_info;
+pub use parse::{Bases, DebugFrame, EhFrame, Error, ParsedFde, Pointer};
@@ -25,22 +25,110 @@
 #![allow(non_upper_case_globals)]
 
 #[cfg(test)]
-mod tests;
+pub(super) mod tests;
 
-use alloc::{format, string::String};
 use core::{ffi::CStr, fmt, ops::ControlFlow};
 
-/// The dwarf is invalid. This is fatal and should never happen.
-#[derive(Debug)]
-pub struct Error(String);
+use super::expr::{self, Memory};
+use crate::arch::Context;
+
+/// The dwarf is invalid, or uses something we don't support. This is fatal
+/// and should never happen.
+///
+/// This doesn't allocate, we may be unwinding because the allocator failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Error {
+    /// The data ended in the middle of a value.
+    Truncated,
+    /// An unknown or unsupported pointer encoding (`DW_EH_PE_*`).
+    BadEncoding(u8),
+    /// A CIE version that we don't support.
+    UnsupportedVersion(u8),
+    /// An unknown character in the augmentation string of a CIE.
+    UnknownAugmentation(u8),
+    /// The augmentation string is not valid UTF-8.
+    InvalidAugmentationString,
+    /// The CIE pointer of an FDE doesn't point to a CIE.
+    InvalidCiePointer,
+    /// An unknown call frame instruction or DWARF expression operation.
+    UnknownOpcode(u8),
+    /// An instruction that isn't allowed where it is, for example
+    /// `DW_CFA_restore` in the initial instructions of a CIE.
+    InvalidInstruction(u8),
+    /// `DW_CFA_remember_state` was nested too deep.
+    StateStackOverflow,
+    /// `DW_CFA_restore_state` without a remembered state.
+    StateStackUnderflow,
+    /// A register number that we don't know about.
+    InvalidRegister(usize),
+    /// A register rule that we can't apply.
+    UnsupportedRule,
+    /// A DWARF expression needs a deeper stack than we have.
+    ExpressionStackOverflow,
+    /// A DWARF expression popped more values than it pushed.
+    ExpressionStackUnderflow,
+    /// A DWARF expression ran for too long, it probably loops.
+    ExpressionTooLong,
+    /// A DWARF expression divided by zero.
+    DivisionByZero,
+    /// A DWARF expression jumped outside of itself.
+    InvalidJump,
+    /// A memory read of an unsupported size.
+    InvalidReadSize(usize),
+    /// The search table of `.eh_frame_hdr` points outside of `.eh_frame`.
+    InvalidFdePointer,
+    /// A CIE with an address size that we don't support.
+    UnsupportedAddressSize(u8),
+    /// A CIE with segment selectors, which we don't support in `.eh_frame`.
+    UnsupportedSegmentSelectorSize(u8),
+    /// A value or a location computed from the CFI doesn't fit in a pointer.
+    Overflow,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Truncated => write!(f, "unexpected end of data"),
+            Error::BadEncoding(encoding) => write!(f, "bad pointer encoding: {encoding:#x}"),
+            Error::UnsupportedVersion(version) => write!(f, "unsupported CIE version: {version}"),
+            Error::UnknownAugmentation(code) => {
+                write!(f, "unknown augmentation: {:?}", *code as char)
+            }
+            Error::InvalidAugmentationString => write!(f, "augmentation string is not UTF-8"),
+            Error::InvalidCiePointer => write!(f, "CIE pointer doesn't point to a CIE"),
+            Error::UnknownOpcode(opcode) => write!(f, "unknown opcode: {opcode:#x}"),
+            Error::InvalidInstruction(opcode) => write!(f, "instruction not allowed: {opcode:#x}"),
+            Error::StateStackOverflow => write!(f, "DW_CFA_remember_state stack overflow"),
+            Error::StateStackUnderflow => {
+                write!(f, "DW_CFA_restore_state without a remembered state")
+            }
+            Error::InvalidRegister(register) => write!(f, "invalid register: {register}"),
+            Error::UnsupportedRule => write!(f, "unsupported register rule"),
+            Error::ExpressionStackOverflow => write!(f, "DWARF expression stack overflow"),
+            Error::ExpressionStackUnderflow => write!(f, "DWARF expression stack underflow"),
+            Error::ExpressionTooLong => write!(f, "DWARF expression takes too long"),
+            Error::DivisionByZero => write!(f, "division by zero in DWARF expression"),
+            Error::InvalidJump => write!(f, "DWARF expression jump out of bounds"),
+            Error::InvalidReadSize(size) => write!(f, "invalid memory read size: {size}"),
+            Error::InvalidFdePointer => write!(f, "FDE pointer outside of .eh_frame"),
+            Error::UnsupportedAddressSize(size) => write!(f, "unsupported address size: {size}"),
+            Error::UnsupportedSegmentSelectorSize(size) => {
+                write!(f, "unsupported segment selector size: {size}")
+            }
+            Error::Overflow => write!(f, "value out of range"),
+        }
+    }
+}
 
-type Result<T, E = Error> = core::result::Result<T, E>;
+pub(crate) type Result<T, E = Error> = core::result::Result<T, E>;
 
-#[derive(Debug)]
-pub struct Expr;
+/// A DWARF expression, as the raw bytes of its operations.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Expr<'a>(pub(crate) &'a [u8]);
 
-#[derive(Debug)]
-enum RegisterRule {
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum RegisterRule<'a> {
     /// A register that has this rule has no recoverable value in the previous
     /// frame. (By convention, it is not preserved by a callee.)
     Undefined,
@@ -59,30 +147,58 @@ enum RegisterRule {
     Register(u16),
     /// The previous value of this register is located at the address produced
     /// by executing the DWARF expression E (see Section 2.5 on page 26)
-    Expression(Expr),
+    Expression(Expr<'a>),
     /// The previous value of this register is the value produced by executing
     /// the DWARF expression E (see Section 2.5 on page 26).
-    ValExpression(Expr),
+    ValExpression(Expr<'a>),
     ///  The rule is defined externally to this specification by the augmenter.
     Architectural,
 }
 
+/// The rule to compute the Canonical Frame Address.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum CfaRule<'a> {
+    /// The CFA is the value of the register plus the (non-factored) offset.
+    RegisterOffset { register: u16, offset: isize },
+    /// The CFA is the value produced by executing the DWARF expression E.
+    Expression(Expr<'a>),
+}
+
+/// A single row of the CFI table, containing the rules to unwind a frame at a
+/// specific location.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct UnwindRow<'a> {
+    pub(crate) cfa: CfaRule<'a>,
+    pub(crate) registers: [RegisterRule<'a>; arch::REGISTER_COUNT],
+    /// The size of the arguments that are pushed on the stack for a call at
+    /// this location (`DW_CFA_GNU_args_size`). Landing pads expect them to be
+    /// popped already.
+    pub(crate) args_size: usize,
+}
+
+impl UnwindRow<'_> {
+    fn new() -> Self {
+        Self {
+            cfa: CfaRule::RegisterOffset {
+                register: 0,
+                offset: 0,
+            },
+            // The spec says that the default rule is undefined, but like
+            // everyone else, we keep the value of registers that the CFI
+            // doesn't mention. Callee-saved registers that weren't touched
+            // wouldn't be mentioned, for example.
+            registers: [RegisterRule::SameValue; arch::REGISTER_COUNT],
+            args_size: 0,
+        }
+    }
+}
+
 type Id = u32;
 
 #[derive(Debug)]
 pub struct ULeb128(u128);
-impl ULeb128 {
-    fn parse() -> Self {
-        todo!()
-    }
-}
 #[derive(Debug)]
 pub struct ILeb128(i128);
-impl ILeb128 {
-    fn parse() -> Self {
-        todo!()
-    }
-}
 
 /// Common Information Entry
 #[derive(Debug, PartialEq, Clone, Copy)]
@@ -107,6 +223,12 @@ pub struct Cie<'a> {
     /// encoding.
     pub augmentation: Option<AugmentationData>,
     pub augmentation_string: &'a str,
+    /// The size of a target address in this CIE and the FDEs that use it. Only
+    /// `.debug_frame` version 4 CIEs have this, otherwise it's the native
+    /// size.
+    pub address_size: u8,
+    /// The size of a segment selector in this CIE and the FDEs that use it.
+    pub segment_selector_size: u8,
     /// A constant that is factored out of all advance location instructions
     /// (see Section 6.4.2.1 on page 177). The resulting value is
     /// (operand * code_alignment_factor).
@@ -126,6 +248,34 @@ pub struct Cie<'a> {
     /// or a compilation system authoring body may specify an alternate
     /// default value for any or all columns.
     pub initial_instructions: &'a [u8],
+    /// The address of the initial instructions when the section is loaded.
+    pub initial_instructions_address: usize,
+}
+
+impl Cie<'_> {
+    /// The encoding of the plain target addresses in `.debug_frame`.
+    fn target_address_encoding(&self) -> Result<Encoding> {
+        match self.address_size {
+            4 => Ok(Encoding(ValueFormat::DW_EH_PE_udata4 as u8)),
+            8 => Ok(Encoding(ValueFormat::DW_EH_PE_udata8 as u8)),
+            size => Err(Error::UnsupportedAddressSize(size)),
+        }
+    }
+
+    /// Multiplies the factored offset of an instruction with the data
+    /// alignment factor.
+    fn data_offset(&self, factored_offset: i128) -> Result<isize> {
+        (factored_offset * self.data_alignment_factor as i128)
+            .try_into()
+            .map_err(|_| Error::Overflow)
+    }
+
+    /// Whether the FDEs of this CIE describe signal trampolines. The
+    /// instruction pointer of the frame they unwind to is the instruction that
+    /// was interrupted, not a return address.
+    pub fn is_signal_frame(&self) -> bool {
+        self.augmentation.is_some_and(|aug| aug.signal_frame)
+    }
 }
 
 /// Frame Description Entry
@@ -159,7 +309,7 @@ pub struct Fde<'a> {
 }
 
 #[derive(Debug)]
-pub enum Instruction {
+pub enum Instruction<'a> {
     //-------- 6.4.2.1 Row Creation Instructions
     //
     /// The DW_CFA_set_loc instruction takes a single operand that represents a
@@ -233,7 +383,7 @@ pub enum Instruction {
     /// as a DW_FORM_exprloc value representing a DWARF expression. The
     /// required action is to establish that expression as the means by which
     /// the current CFA is computed.
-    DefCfaExpression(Expr),
+    DefCfaExpression(Expr<'a>),
     //
     //-------- 6.4.2.3 Register Rule Instructions
     //
@@ -306,7 +456,7 @@ pub enum Instruction {
     /// pushed on the DWARF evaluation stack prior to execution of the DWARF
     /// expression. See Section 6.4.2 on page 176 regarding restrictions on
     /// the DWARF expression operators that can be used.
-    Expression { register: ULeb128, expr: Expr },
+    Expression { register: ULeb128, expr: Expr<'a> },
     /// The DW_CFA_val_expression instruction takes two operands: an unsigned
     /// LEB128 value representing a register number, and a DW_FORM_block value
     /// representing a DWARF expression. The required action is to change the
@@ -317,7 +467,7 @@ pub enum Instruction {
     /// execution of the DWARF expression. See Section 6.4.2 on page 176
     /// regarding restrictions on the DWARF expression operators that can be
     /// used.
-    ValExpression { register: ULeb128, expr: Expr },
+    ValExpression { register: ULeb128, expr: Expr<'a> },
     /// The DW_CFA_restore instruction takes a single operand (encoded with the
     /// opcode) that represents a register number. The required action is to
     /// change the rule for the indicated register to the rule assigned it
@@ -353,45 +503,124 @@ enum FrameInfo<'a> {
     Fde(Fde<'a>),
 }
 
-struct Cursor<'a>(&'a [u8]);
+pub(super) struct Cursor<'a>(pub(super) &'a [u8]);
+
+/// The base addresses that `DW_EH_PE_textrel`, `DW_EH_PE_datarel` and
+/// `DW_EH_PE_funcrel` values are relative to.
+///
+/// The text and data bases are not used on x86-64, where the toolchains only
+/// emit `pcrel` and `absptr` encodings. Like libgcc, we report them as 0 there.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Bases {
+    /// The start of the `.text` section of the module.
+    pub text: usize,
+    /// The start of the `.got` section of the module, or of `.eh_frame_hdr`
+    /// for the values in it.
+    pub data: usize,
+    /// The start of the function described by an FDE. This is 0 until we have
+    /// read the initial location of the FDE.
+    pub func: usize,
+}
+
+/// A pointer read from the CFI. A `DW_EH_PE_indirect` pointer is the address
+/// of the real pointer, which we can only read if the CFI is loaded in our
+/// process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pointer {
+    Direct(usize),
+    Indirect(usize),
+}
 
-/// Returns `(read_size, value)`
-pub(super) unsafe fn read_encoded(
-    ptr: *const u8,
+impl Pointer {
+    /// Returns the real pointer, reading it from memory if it is indirect.
+    ///
+    /// # Safety
+    /// The pointer must come from CFI that is loaded in our process.
+    pub(crate) unsafe fn resolve(self) -> usize {
+        match self {
+            Pointer::Direct(pointer) => pointer,
+            Pointer::Indirect(address) => {
+                core::ptr::with_exposed_provenance::<usize>(address).read_unaligned()
+            }
+        }
+    }
+}
+
+/// Reads a pointer with the given encoding. `address` is the address of the
+/// value when its section is loaded, which `DW_EH_PE_pcrel` is relative to.
+pub(super) fn read_pointer(
+    data: &mut Cursor<'_>,
+    address: usize,
     encoding: Encoding,
-    datarel_base: Option<usize>,
-) -> (usize, usize) {
-    let (read_size, value) = match encoding.format() {
-        ValueFormat::DW_EH_PE_uleb128 => todo!("uleb128"),
-        ValueFormat::DW_EH_PE_udata2 => (2, ptr.cast::<u16>().read_unaligned() as usize),
-        ValueFormat::DW_EH_PE_udata4 => (4, ptr.cast::<u32>().read_unaligned() as usize),
-        ValueFormat::DW_EH_PE_udata8 => (8, ptr.cast::<u64>().read_unaligned() as usize),
-        ValueFormat::DW_EH_PE_sleb128 => todo!("sleb128"),
-        ValueFormat::DW_EH_PE_sdata2 => (2, ptr.cast::<i16>().read_unaligned() as isize as usize),
-        ValueFormat::DW_EH_PE_sdata4 => (4, ptr.cast::<i32>().read_unaligned() as isize as usize),
-        ValueFormat::DW_EH_PE_sdata8 => (8, ptr.cast::<i64>().read_unaligned() as isize as usize),
-    };
+    bases: &Bases,
+) -> Result<Pointer> {
+    // Callers check for omitted values, there is nothing to read.
+    if encoding.is_omit() {
+        return Err(Error::BadEncoding(encoding.0));
+    }
 
-    let value = match encoding.application() {
-        ValueApplication::DW_EH_PE_absptr => value,
-        ValueApplication::DW_EH_PE_pcrel => ((value as isize) + (ptr as isize)) as usize,
-        ValueApplication::DW_EH_PE_textrel => todo!("textrel"),
-        ValueApplication::DW_EH_PE_datarel => {
-            ((value as isize) + (datarel_base.unwrap() as isize)) as usize
+    let application = encoding.application()?;
+    let value = if let ValueApplication::DW_EH_PE_aligned = application {
+        // The value is a native pointer, aligned to its size in memory.
+        let padding = address.wrapping_neg() % size_of::<usize>();
+        read_bytes(data, padding)?;
+        read_usize(data)?
+    } else {
+        match encoding.format()? {
+            ValueFormat::DW_EH_PE_absptr => read_usize(data)?,
+            ValueFormat::DW_EH_PE_uleb128 => read_uleb128(data)?,
+            ValueFormat::DW_EH_PE_sleb128 => read_ileb128(data)? as usize,
+            ValueFormat::DW_EH_PE_udata2 => read_u16(data)? as usize,
+            ValueFormat::DW_EH_PE_udata4 => read_u32(data)? as usize,
+            ValueFormat::DW_EH_PE_udata8 => read_u64(data)? as usize,
+            ValueFormat::DW_EH_PE_sdata2 => read_u16(data)? as i16 as isize as usize,
+            ValueFormat::DW_EH_PE_sdata4 => read_u32(data)? as i32 as isize as usize,
+            ValueFormat::DW_EH_PE_sdata8 => read_u64(data)? as i64 as isize as usize,
         }
-        ValueApplication::DW_EH_PE_funcrel => todo!("funcrel"),
-        ValueApplication::DW_EH_PE_aligned => todo!("aligned"),
     };
 
-    (read_size, value)
+    // Like libgcc, a zero value is a null pointer, with no base applied and
+    // nothing to read through.
+    if value == 0 {
+        return Ok(Pointer::Direct(0));
+    }
+
+    let value = match application {
+        ValueApplication::DW_EH_PE_absptr | ValueApplication::DW_EH_PE_aligned => value,
+        ValueApplication::DW_EH_PE_pcrel => value.wrapping_add(address),
+        ValueApplication::DW_EH_PE_textrel => value.wrapping_add(bases.text),
+        ValueApplication::DW_EH_PE_datarel => value.wrapping_add(bases.data),
+        ValueApplication::DW_EH_PE_funcrel => value.wrapping_add(bases.func),
+    };
+
+    if encoding.is_indirect() {
+        Ok(Pointer::Indirect(value))
+    } else {
+        Ok(Pointer::Direct(value))
+    }
+}
+
+/// Reads a value with the given encoding, like [`read_pointer`], for values
+/// that can't be indirect.
+pub(super) fn read_encoded(
+    data: &mut Cursor<'_>,
+    address: usize,
+    encoding: Encoding,
+    bases: &Bases,
+) -> Result<usize> {
+    match read_pointer(data, address, encoding, bases)? {
+        Pointer::Direct(value) => Ok(value),
+        Pointer::Indirect(_) => Err(Error::BadEncoding(encoding.0)),
+    }
 }
 
 #[derive(PartialEq, Clone, Copy)]
 #[repr(transparent)]
 pub(super) struct Encoding(u8);
 impl Encoding {
-    fn format(&self) -> ValueFormat {
-        match self.0 & 0b1111 {
+    fn format(&self) -> Result<ValueFormat> {
+        Ok(match self.0 & 0b1111 {
+            0x00 => ValueFormat::DW_EH_PE_absptr,
             0x01 => ValueFormat::DW_EH_PE_uleb128,
             0x02 => ValueFormat::DW_EH_PE_udata2,
             0x03 => ValueFormat::DW_EH_PE_udata4,
@@ -400,44 +629,69 @@ impl Encoding {
             0x0A => ValueFormat::DW_EH_PE_sdata2,
             0x0B => ValueFormat::DW_EH_PE_sdata4,
             0x0C => ValueFormat::DW_EH_PE_sdata8,
-            _ => panic!("Invalid header value format"),
-        }
+            _ => return Err(Error::BadEncoding(self.0)),
+        })
     }
-    fn application(&self) -> ValueApplication {
-        match self.0 >> 4 {
+    fn application(&self) -> Result<ValueApplication> {
+        Ok(match (self.0 & 0b0111_0000) >> 4 {
             0x0 => ValueApplication::DW_EH_PE_absptr,
             0x1 => ValueApplication::DW_EH_PE_pcrel,
             0x2 => ValueApplication::DW_EH_PE_textrel,
             0x3 => ValueApplication::DW_EH_PE_datarel,
             0x4 => ValueApplication::DW_EH_PE_funcrel,
             0x5 => ValueApplication::DW_EH_PE_aligned,
-            v => panic!("invalid header value application: {v}"),
-        }
+            _ => return Err(Error::BadEncoding(self.0)),
+        })
+    }
+    /// The value is not present at all.
+    pub(super) fn is_omit(&self) -> bool {
+        self.0 == DW_EH_PE_omit
     }
-    pub(crate) fn size(&self) -> usize {
-        match self.format() {
-            ValueFormat::DW_EH_PE_uleb128 => panic!("uleb128 has no known size"),
-            ValueFormat::DW_EH_PE_udata2 => 2,
-            ValueFormat::DW_EH_PE_udata4 => 4,
-            ValueFormat::DW_EH_PE_udata8 => 8,
-            ValueFormat::DW_EH_PE_sleb128 => panic!("sleb128 has no known size"),
-            ValueFormat::DW_EH_PE_sdata2 => 2,
-            ValueFormat::DW_EH_PE_sdata4 => 4,
-            ValueFormat::DW_EH_PE_sdata8 => 8,
+    fn format_only(&self) -> Encoding {
+        Encoding(self.0 & 0b1111)
+    }
+    /// The value is not the pointer itself, but the address of it.
+    fn is_indirect(&self) -> bool {
+        (self.0 & DW_EH_PE_indirect) != 0
+    }
+    /// The size of values with this encoding. LEB128 values don't have a
+    /// fixed size.
+    pub(crate) fn size(&self) -> Result<usize> {
+        match self.format()? {
+            ValueFormat::DW_EH_PE_absptr => Ok(size_of::<usize>()),
+            ValueFormat::DW_EH_PE_udata2 | ValueFormat::DW_EH_PE_sdata2 => Ok(2),
+            ValueFormat::DW_EH_PE_udata4 | ValueFormat::DW_EH_PE_sdata4 => Ok(4),
+            ValueFormat::DW_EH_PE_udata8 | ValueFormat::DW_EH_PE_sdata8 => Ok(8),
+            ValueFormat::DW_EH_PE_uleb128 | ValueFormat::DW_EH_PE_sleb128 => {
+                Err(Error::BadEncoding(self.0))
+            }
         }
     }
 }
 
 impl fmt::Debug for Encoding {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?} | {:?}", self.application(), self.format())
+        match (self.is_omit(), self.application(), self.format()) {
+            (true, _, _) => write!(f, "DW_EH_PE_omit"),
+            (false, Ok(application), Ok(format)) => write!(f, "{application:?} | {format:?}"),
+            _ => write!(f, "invalid encoding {:#x}", self.0),
+        }
     }
 }
 
+/// Bit flag that can be added to any encoding: the value is the address of
+/// the real value.
+const DW_EH_PE_indirect: u8 = 0x80;
+
+/// Used instead of an encoding when the value is not present.
+const DW_EH_PE_omit: u8 = 0xff;
+
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 #[allow(non_camel_case_types)]
 enum ValueFormat {
+    /// A pointer of the native size.
+    DW_EH_PE_absptr = 0x00,
     /// Unsigned value is encoded using the Little Endian Base 128 (LEB128) as
     /// defined by DWARF Debugging Information Format, Revision 2.0.0 (July 27,
     /// 1993).
@@ -465,54 +719,76 @@ enum ValueFormat {
 #[allow(non_camel_case_types)]
 enum ValueApplication {
     DW_EH_PE_absptr = 0x00,
-    ///	Value is relative to the current program counter.
+    /// Value is relative to the current program counter.
     DW_EH_PE_pcrel = 0x10,
-    ///	Value is relative to the beginning of the .text section.
+    /// Value is relative to the beginning of the .text section.
     DW_EH_PE_textrel = 0x20,
-    ///	Value is relative to the beginning of the .got or .eh_frame_hdr
+    /// Value is relative to the beginning of the .got or .eh_frame_hdr
     /// section.
     DW_EH_PE_datarel = 0x30,
-    ///	Value is relative to the beginning of the function.
+    /// Value is relative to the beginning of the function.
     DW_EH_PE_funcrel = 0x40,
-    ///	Value is aligned to an address unit sized boundary.
+    /// Value is aligned to an address unit sized boundary.
     DW_EH_PE_aligned = 0x50,
 }
 
-fn read_bytes<'a>(data: &mut Cursor<'a>, amount: usize) -> Result<&'a [u8]> {
+pub(super) fn read_bytes<'a>(data: &mut Cursor<'a>, amount: usize) -> Result<&'a [u8]> {
     if data.0.len() < amount {
-        return Err(Error(format!(
-            "index out of bounds, tried to read {amount} bytes from {}",
-            data.0.len()
-        )));
+        Err(Error::Truncated)
     } else {
         let result = &data.0[..amount];
         data.0 = &data.0[amount..];
         Ok(result)
     }
 }
+fn read_u16(data: &mut Cursor<'_>) -> Result<u16> {
+    let int = read_bytes(data, 2)?;
+    Ok(u16::from_le_bytes([int[0], int[1]]))
+}
 fn read_u32(data: &mut Cursor<'_>) -> Result<u32> {
     let int = read_bytes(data, 4)?;
-    Ok(u32::from_le_bytes(int.try_into().unwrap()))
+    Ok(u32::from_le_bytes([int[0], int[1], int[2], int[3]]))
+}
+fn read_u64(data: &mut Cursor<'_>) -> Result<u64> {
+    let int = read_bytes(data, 8)?;
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(int);
+    Ok(u64::from_le_bytes(bytes))
 }
-fn read_u8(data: &mut Cursor<'_>) -> Result<u8> {
+fn read_usize(data: &mut Cursor<'_>) -> Result<usize> {
+    let int = read_bytes(data, size_of::<usize>())?;
+    let mut bytes = [0; size_of::<usize>()];
+    bytes.copy_from_slice(int);
+    Ok(usize::from_le_bytes(bytes))
+}
+pub(super) fn read_u8(data: &mut Cursor<'_>) -> Result<u8> {
     let int = read_bytes(data, 1)?;
     Ok(int[0])
 }
 fn read_utf8_cstr<'a>(data: &mut Cursor<'a>) -> Result<&'a str> {
-    let cstr: &CStr = CStr::from_bytes_until_nul(data.0)
-        .map_err(|_| Error("no null terminator found for string".into()))?;
+    let cstr: &CStr = CStr::from_bytes_until_nul(data.0).map_err(|_| Error::Truncated)?;
     let utf8 = cstr
         .to_str()
-        .map_err(|e| Error(format!("invalid utf8: {e:?}")))?;
+        .map_err(|_| Error::InvalidAugmentationString)?;
     data.0 = &data.0[(utf8.len() + 1)..];
     Ok(utf8)
 }
-fn read_uleb128(data: &mut Cursor<'_>) -> Result<usize> {
+pub(super) fn read_uleb128(data: &mut Cursor<'_>) -> Result<usize> {
     let mut result = 0;
     let mut shift = 0;
     loop {
         let byte = read_u8(data)?;
-        result |= ((byte & 0b0111_1111) << shift) as usize;
+        let bits = (byte & 0b0111_1111) as usize;
+        // Padding bytes past the size of a pointer are fine, bits aren't.
+        if shift >= usize::BITS {
+            if bits != 0 {
+                return Err(Error::Overflow);
+            }
+        } else if (bits << shift) >> shift != bits {
+            return Err(Error::Overflow);
+        } else {
+            result |= bits << shift;
+        }
         if (byte >> 7) == 0 {
             break;
         }
@@ -520,14 +796,20 @@ fn read_uleb128(data: &mut Cursor<'_>) -> Result<usize> {
     }
     Ok(result)
 }
-fn read_ileb128(data: &mut Cursor<'_>) -> Result<isize> {
+pub(super) fn read_ileb128(data: &mut Cursor<'_>) -> Result<isize> {
     let mut result = 0;
     let mut shift = 0;
-    let size = 8;
+    let size = isize::BITS;
 
     let sign_bit_set = loop {
         let byte = read_u8(data)?;
-        result |= ((byte & 0b0111_1111) << shift) as isize;
+        let bits = (byte & 0b0111_1111) as isize;
+        if shift < size {
+            result |= bits << shift;
+        } else if bits != 0 && bits != 0b0111_1111 {
+            // Past the size of a pointer, only the sign extension may follow.
+            return Err(Error::Overflow);
+        }
         shift += 7;
         if (byte >> 7) == 0 {
             let sign_bit_set = ((byte >> 6) & 1) == 1;
@@ -535,49 +817,279 @@ fn read_ileb128(data: &mut Cursor<'_>) -> Result<isize> {
         }
     };
     if (shift < size) && sign_bit_set {
-        result |= -(1 << shift);
+        result |= !0 << shift;
     }
     Ok(result)
 }
 
-unsafe fn parse_frame_head<'a>(ptr: *const u8) -> Result<(u32, &'a [u8], *const u8)> {
-    let len = ptr.cast::<u32>().read();
-    if len == 0xffffffff {
-        // be careful, if you handle this you need to adjust the callers offsets lol
-        // lmao
-        todo!("loooong dwarf, cannot handle.");
+/// Whether an entry uses the 32-bit or the 64-bit DWARF format (DWARF5 §7.4).
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Dwarf32,
+    Dwarf64,
+}
+
+/// The part that CIEs and FDEs have in common.
+#[derive(Debug)]
+struct FrameHead<'a> {
+    format: Format,
+    /// The CIE id of a CIE, or the CIE pointer of an FDE. This is 4 bytes in
+    /// `.eh_frame` even in the 64-bit format.
+    id: u64,
+    /// The offset of the id field in the section, which the CIE pointer is
+    /// relative to in `.eh_frame`.
+    id_offset: usize,
+    /// The rest of the entry after the id.
+    data: &'a [u8],
+    /// The offset of the entry after this one.
+    next: usize,
+}
+
+/// Parses the head of the entry at `offset` in `section`. Returns `None` for
+/// the zero terminator at the end of the section.
+fn parse_frame_head<'a>(section: &Section<'a>, offset: usize) -> Result<Option<FrameHead<'a>>> {
+    let bytes = section.bytes;
+    let data = &mut Cursor(bytes.get(offset..).ok_or(Error::Truncated)?);
+    let (format, len) = match read_u32(data)? {
+        0 => return Ok(None),
+        // An initial length of 0xffffffff is followed by the real 64-bit length.
+        0xffffffff => (Format::Dwarf64, read_u64(data)? as usize),
+        len => (Format::Dwarf32, len as usize),
+    };
+    let id_offset = bytes.len() - data.0.len();
+    let data = &mut Cursor(read_bytes(data, len)?);
+    trace!(?format, "frame info entry (without len): {:x?}", data.0);
+
+    let id = match (section.kind, format) {
+        (SectionKind::DebugFrame, Format::Dwarf64) => read_u64(data)?,
+        _ => read_u32(data)?.into(),
+    };
+
+    Ok(Some(FrameHead {
+        format,
+        id,
+        id_offset,
+        data: data.0,
+        next: id_offset + len,
+    }))
+}
+
+/// The sections that contain call frame information. `.debug_frame` is the
+/// format of the DWARF standard, `.eh_frame` is the one that is loaded at
+/// runtime. It differs in the details.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SectionKind {
+    EhFrame,
+    DebugFrame,
+}
+
+/// A section with call frame information.
+///
+/// This only looks at the bytes of the section, so it works just as well for
+/// sections that are not loaded in our process. Every read is bounds checked.
+#[derive(Debug, Clone, Copy)]
+struct Section<'a> {
+    kind: SectionKind,
+    bytes: &'a [u8],
+    /// The address of the section when it is loaded, which PC-relative
+    /// pointers are relative to.
+    base_address: u64,
+    bases: Bases,
+    /// The size of a target address, for CIEs that don't specify it.
+    address_size: u8,
+}
+
+impl<'a> Section<'a> {
+    /// The address of `data`, a part of the section, when it is loaded.
+    fn address_of(&self, data: &[u8]) -> usize {
+        let offset = data
+            .as_ptr()
+            .addr()
+            .wrapping_sub(self.bytes.as_ptr().addr());
+        (self.base_address as usize).wrapping_add(offset)
+    }
+
+    fn is_cie(&self, head: &FrameHead<'_>) -> bool {
+        match (self.kind, head.format) {
+            (SectionKind::EhFrame, _) => head.id == 0,
+            (SectionKind::DebugFrame, Format::Dwarf32) => head.id == 0xffffffff,
+            (SectionKind::DebugFrame, Format::Dwarf64) => head.id == u64::MAX,
+        }
+    }
+
+    fn fde_at(&self, offset: usize) -> Result<ParsedFde<'a>> {
+        let fde_head = parse_frame_head(self, offset)?.ok_or(Error::InvalidFdePointer)?;
+        if self.is_cie(&fde_head) {
+            return Err(Error::InvalidFdePointer);
+        }
+        trace!("FDE's CIE pointer: {}", fde_head.id);
+
+        let cie_offset = match self.kind {
+            // The CIE pointer is relative to its own location, which comes
+            // after the length and so depends on the format.
+            SectionKind::EhFrame => fde_head.id_offset.checked_sub(fde_head.id as usize),
+            // The CIE pointer is an offset in the section.
+            SectionKind::DebugFrame => usize::try_from(fde_head.id).ok(),
+        }
+        .ok_or(Error::InvalidCiePointer)?;
+        trace!("CIE offset: {cie_offset:x}");
+
+        let cie_head = parse_frame_head(self, cie_offset)?.ok_or(Error::InvalidCiePointer)?;
+        if !self.is_cie(&cie_head) {
+            return Err(Error::InvalidCiePointer);
+        }
+        let cie = parse_cie(&mut Cursor(cie_head.data), self)?;
+
+        let address = self.address_of(&self.bytes[offset..]);
+        parse_fde(&mut Cursor(fde_head.data), self, address, cie)
+    }
+
+    fn find_fde(&self, addr: usize) -> Result<Option<ParsedFde<'a>>> {
+        let mut offset = 0;
+        while offset < self.bytes.len() {
+            let Some(head) = parse_frame_head(self, offset)? else {
+                break;
+            };
+            // CIEs are only interesting through their FDEs.
+            if !self.is_cie(&head) {
+                let fde = self.fde_at(offset)?;
+                if fde.covers(addr) {
+                    return Ok(Some(fde));
+                }
+            }
+            offset = head.next;
+        }
+
+        Ok(None)
+    }
+}
+
+/// An `.eh_frame` section.
+///
+/// This only looks at the bytes of the section, so it works just as well for
+/// sections that are not loaded in our process. Every read is bounds checked.
+#[derive(Debug, Clone, Copy)]
+pub struct EhFrame<'a>(Section<'a>);
+
+impl<'a> EhFrame<'a> {
+    /// The section `bytes`, which is loaded at `base_address`.
+    pub fn new(bytes: &'a [u8], base_address: u64) -> Self {
+        EhFrame(Section {
+            kind: SectionKind::EhFrame,
+            bytes,
+            base_address,
+            bases: Bases::default(),
+            address_size: size_of::<usize>() as u8,
+        })
+    }
+
+    /// Sets the text and data bases for pointers in the section. The function
+    /// base is filled in from every FDE.
+    pub fn with_bases(self, bases: Bases) -> Self {
+        EhFrame(Section { bases, ..self.0 })
+    }
+
+    /// A section that is loaded in our own process.
+    pub(crate) fn in_process(bytes: &'a [u8]) -> Self {
+        Self::new(bytes, bytes.as_ptr().addr() as u64)
+    }
+
+    /// Parses the FDE at `offset` in the section, together with its CIE.
+    pub fn fde_at(&self, offset: usize) -> Result<ParsedFde<'a>> {
+        self.0.fde_at(offset)
     }
-    let data = &mut Cursor(core::slice::from_raw_parts(ptr.add(4), len as usize));
-    trace!("frame info entry (without len): {:x?}", data.0);
 
-    let cie_id = read_u32(data)?;
-    let new_ptr = ptr.add(4).add(len as _);
+    /// Finds the FDE covering `addr` by walking all entries of the section, up
+    /// to its end or the zero terminator. This is slow, it's only used when
+    /// there is no `.eh_frame_hdr` search table.
+    pub fn find_fde(&self, addr: usize) -> Result<Option<ParsedFde<'a>>> {
+        self.0.find_fde(addr)
+    }
+}
+
+/// A `.debug_frame` section, for binaries that don't have `.eh_frame`.
+///
+/// It has no pointer encodings, all addresses in it are absolute. The section
+/// is usually not loaded, so the address of an FDE is its offset in the
+/// section.
+#[derive(Debug, Clone, Copy)]
+pub struct DebugFrame<'a>(Section<'a>);
+
+impl<'a> DebugFrame<'a> {
+    /// The section `bytes` of a target with `address_size` byte addresses.
+    /// Only CIEs before version 4 need the address size, the others specify
+    /// it themselves.
+    pub fn new(bytes: &'a [u8], address_size: u8) -> Self {
+        DebugFrame(Section {
+            kind: SectionKind::DebugFrame,
+            bytes,
+            base_address: 0,
+            bases: Bases::default(),
+            address_size,
+        })
+    }
+
+    /// Parses the FDE at `offset` in the section, together with its CIE.
+    pub fn fde_at(&self, offset: usize) -> Result<ParsedFde<'a>> {
+        self.0.fde_at(offset)
+    }
 
-    Ok((cie_id, data.0, new_ptr))
+    /// Finds the FDE covering `addr` by walking all entries of the section.
+    pub fn find_fde(&self, addr: usize) -> Result<Option<ParsedFde<'a>>> {
+        self.0.find_fde(addr)
+    }
 }
 
-#[instrument(skip(data))]
-fn parse_cie<'a>(data: &mut Cursor<'a>) -> Result<Cie<'a>> {
+#[instrument(skip(data, section))]
+fn parse_cie<'a>(data: &mut Cursor<'a>, section: &Section<'a>) -> Result<Cie<'a>> {
     let version = read_u8(data)?;
-    if version != 1 {
-        return Err(Error(format!("version must be 1: {version}")));
+    // GCC emits version 3 when the return address register doesn't fit in a
+    // byte, and version 4 adds the address and segment selector sizes.
+    if !matches!(version, 1 | 3 | 4) {
+        return Err(Error::UnsupportedVersion(version));
     }
 
     let augmentation = read_utf8_cstr(data)?;
+    let (address_size, segment_selector_size) = if version >= 4 {
+        (read_u8(data)?, read_u8(data)?)
+    } else {
+        (section.address_size, 0)
+    };
+    // Pointers in `.eh_frame` are native, there is nothing to select.
+    if section.kind == SectionKind::EhFrame {
+        if usize::from(address_size) != size_of::<usize>() {
+            return Err(Error::UnsupportedAddressSize(address_size));
+        }
+        if segment_selector_size != 0 {
+            return Err(Error::UnsupportedSegmentSelectorSize(segment_selector_size));
+        }
+    }
     let code_alignment_factor = read_uleb128(data)?;
     let data_alignment_factor = read_ileb128(data)?;
-    let return_address_register = read_uleb128(data)?;
+    // The return address register was a ubyte before version 3.
+    let return_address_register = if version == 1 {
+        read_u8(data)?.into()
+    } else {
+        read_uleb128(data)?
+    };
 
-    let augmentation_data = if augmentation.starts_with('z') {
-        let aug_len = read_uleb128(data)?;
-        let aug_data = read_bytes(data, aug_len as usize)?;
+    let augmentation_data = match section.kind {
+        SectionKind::EhFrame if augmentation.starts_with('z') => {
+            let aug_len = read_uleb128(data)?;
+            let aug_data = read_bytes(data, aug_len as usize)?;
 
-        let aug = parse_augmentation_data(augmentation, aug_data)?;
-        trace!("AUGMENTATION {aug:?}");
+            let aug = parse_augmentation_data(augmentation, aug_data, section)?;
+            trace!("AUGMENTATION {aug:?}");
 
-        Some(aug)
-    } else {
-        None
+            Some(aug)
+        }
+        SectionKind::EhFrame => None,
+        // We wouldn't know how to parse the FDEs of a `.debug_frame`
+        // augmentation.
+        SectionKind::DebugFrame => match augmentation.bytes().next() {
+            Some(code) => return Err(Error::UnknownAugmentation(code)),
+            None => None,
+        },
     };
 
     let initial_instructions = data.0;
@@ -585,99 +1097,117 @@ fn parse_cie<'a>(data: &mut Cursor<'a>) -> Result<Cie<'a>> {
     let cie = Cie {
         augmentation: augmentation_data,
         augmentation_string: augmentation,
+        address_size,
+        segment_selector_size,
         code_alignment_factor,
         data_alignment_factor,
         return_address_register,
         initial_instructions,
+        initial_instructions_address: section.address_of(initial_instructions),
     };
 
     trace!("{cie:?}");
     Ok(cie)
 }
 
-pub(crate) unsafe fn parse_fde_from_ptr<'a>(
-    ptr: *const u8,
-    eh_frame_base: usize,
-) -> Result<ParsedFde<'a>> {
-    let (fde_cie_id, fde_data, _) = parse_frame_head(ptr)?;
-    let fde_data = &mut Cursor(fde_data);
-
-    if fde_cie_id == 0 {
-        return Err(Error(format!("FDE's CIE Pointer is 0")));
-    }
-    trace!("FDE's CIE pointer: {fde_cie_id}");
-
-    let cie_ptr = ptr.byte_add(4 /* length */).byte_sub(fde_cie_id as usize);
-
-    trace!(
-        "CIE offset to .eh_frame: {:x}",
-        cie_ptr.addr() - (eh_frame_base)
-    );
-
-    let (cie_cie_id, cie_data, _) = parse_frame_head(cie_ptr)?;
-    if cie_cie_id != 0 {
-        return Err(Error(format!("CIE must have cie_id=0")));
-    }
-    let cie_data = &mut Cursor(cie_data);
-    let cie = parse_cie(cie_data).unwrap();
-
-    let fde = parse_fde(fde_data, fde_cie_id, cie).unwrap();
-
-    Ok(fde)
+/// An FDE together with its CIE.
+#[derive(Debug, Clone, Copy)]
+pub struct ParsedFde<'a> {
+    /// The address of the FDE itself (its length field).
+    pub address: usize,
+    pub initial_location: usize,
+    pub address_range: usize,
+    pub initial_instructions: &'a [u8],
+    pub instructions: &'a [u8],
+    /// The address of the instructions when the section is loaded.
+    pub instructions_address: usize,
+    /// The base addresses for pointers in the FDE and its instructions.
+    pub bases: Bases,
+    pub cie: Cie<'a>,
+    /// The address of the language-specific data area for the personality
+    /// routine, if the CIE has an `L` augmentation.
+    pub lsda: Option<Pointer>,
 }
 
-pub(crate) struct ParsedFde<'a> {
-    pub(crate) initial_location: usize,
-    pub(crate) address_range: usize,
-    pub(crate) initial_instructions: &'a [u8],
-    pub(crate) instructions: &'a [u8],
-    pub(crate) cie: Cie<'a>,
+impl ParsedFde<'_> {
+    /// Whether `addr` is in the function described by the FDE. The range may
+    /// be garbage, so this can't overflow.
+    pub(crate) fn covers(&self, addr: usize) -> bool {
+        addr.wrapping_sub(self.initial_location) < self.address_range
+    }
 }
 
-#[instrument(skip(data))]
-fn parse_fde<'a>(data: &mut Cursor<'a>, cie_id: u32, cie: Cie<'a>) -> Result<ParsedFde<'a>> {
+#[instrument(skip(data, section))]
+fn parse_fde<'a>(
+    data: &mut Cursor<'a>,
+    section: &Section<'a>,
+    address: usize,
+    cie: Cie<'a>,
+) -> Result<ParsedFde<'a>> {
     trace!("FDE {:x?}", data.0);
 
-    let augmentation = cie
-        .augmentation
-        .as_ref()
-        .ok_or_else(|| Error("augmentation data not present for CIE with FDEs".into()))?;
-
-    trace!("augmentation: {augmentation:?}");
-
-    let pointer_encoding = augmentation.pointer_encoding.ok_or_else(|| {
-        Error("pointer encoding not present in augmentation for CIE with FDEs".into())
-    })?;
-
-    let (read_size, initial_location) =
-        unsafe { read_encoded(data.0.as_ptr(), pointer_encoding, None) };
-    data.0 = &data.0[read_size..];
-
-    let (read_size, address_range) =
-        unsafe { read_encoded(data.0.as_ptr(), pointer_encoding, None) };
-    data.0 = &data.0[read_size..];
-
-    // This is only present if the aug data of the CIE contains z. But that is
-    // always the case?
-    if cie.augmentation.is_some() {
-        let augmentation_len = read_uleb128(data)?;
-        if augmentation_len != 0 {
-            trace!(%augmentation_len, "augmentation length");
-            let augmentation_data = &data.0[..(augmentation_len as usize)];
-            let data = parse_augmentation_data(cie.augmentation_string, augmentation_data)?;
-            trace!("debug data: {data:?}");
-            todo!()
+    trace!("augmentation: {:?}", cie.augmentation);
+
+    let pointer_encoding = match section.kind {
+        // Without an `R` augmentation, the addresses are native pointers.
+        SectionKind::EhFrame => cie
+            .augmentation
+            .and_then(|augmentation| augmentation.pointer_encoding)
+            .unwrap_or(Encoding(ValueFormat::DW_EH_PE_absptr as u8)),
+        // `.debug_frame` has plain target addresses, after a segment selector
+        // that we don't care about.
+        SectionKind::DebugFrame => {
+            read_bytes(data, cie.segment_selector_size.into())?;
+            cie.target_address_encoding()?
         }
-    }
+    };
+
+    let initial_location = read_encoded(
+        data,
+        section.address_of(data.0),
+        pointer_encoding,
+        &section.bases,
+    )?;
+    // The address range is a plain size, only the format applies to it.
+    let address_range = read_encoded(data, 0, pointer_encoding.format_only(), &Bases::default())?;
+    let bases = Bases {
+        func: initial_location,
+        ..section.bases
+    };
+
+    // The augmentation data is only present if the CIE augmentation string
+    // contains z. The only thing in it that we know about is the LSDA
+    // pointer, any other data is skipped.
+    let lsda = match cie.augmentation {
+        Some(augmentation) => {
+            let augmentation_len = read_uleb128(data)?;
+            let augmentation_data = read_bytes(data, augmentation_len)?;
+            trace!(%augmentation_len, "augmentation data: {augmentation_data:x?}");
+
+            let address = section.address_of(augmentation_data);
+            augmentation
+                .lsda_pointer_encoding
+                .map(|encoding| {
+                    read_pointer(&mut Cursor(augmentation_data), address, encoding, &bases)
+                })
+                .transpose()?
+        }
+        None => None,
+    };
+    trace!("LSDA: {lsda:x?}");
 
     trace!("fde rest: {:x?}", data.0);
 
     Ok(ParsedFde {
+        address,
         initial_location,
         address_range,
         instructions: data.0,
+        instructions_address: section.address_of(data.0),
         initial_instructions: cie.initial_instructions,
+        bases,
         cie,
+        lsda,
     })
 }
 
@@ -685,20 +1215,39 @@ fn parse_fde<'a>(data: &mut Cursor<'a>, cie_id: u32, cie: Cie<'a>) -> Result<Par
 pub struct AugmentationData {
     pub(super) lsda_pointer_encoding: Option<Encoding>,
     pub(super) pointer_encoding: Option<Encoding>,
-    pub(super) personality: Option<usize>,
+    pub(crate) personality: Option<Pointer>,
+    /// `S`: The FDEs describe signal trampolines, so the frames they unwind
+    /// to were interrupted instead of making a call.
+    pub(crate) signal_frame: bool,
+    /// `B`: The code is protected by AArch64 branch target identification.
+    pub(crate) branch_target_protection: bool,
+    /// `G`: The frames use AArch64 memory tagging for their stack.
+    pub(crate) memory_tagged: bool,
 }
 
-fn parse_augmentation_data(string: &str, data: &[u8]) -> Result<AugmentationData> {
+fn parse_augmentation_data(
+    string: &str,
+    data: &[u8],
+    section: &Section<'_>,
+) -> Result<AugmentationData> {
     let data = &mut Cursor(data);
 
+    // The data can only be found through the length that `z` adds, so it has
+    // to come first. An empty string reports its NUL terminator.
     let mut codes = string.bytes();
-    assert_eq!(codes.next(), Some(b'z'));
+    match codes.next() {
+        Some(b'z') => {}
+        code => return Err(Error::UnknownAugmentation(code.unwrap_or(0))),
+    }
     trace!("aug data {:?} | {:x?}", string, data.0);
 
     let mut aug_data = AugmentationData {
         pointer_encoding: None,
         lsda_pointer_encoding: None,
         personality: None,
+        signal_frame: false,
+        branch_target_protection: false,
+        memory_tagged: false,
     };
 
     for code in codes {
@@ -711,8 +1260,8 @@ fn parse_augmentation_data(string: &str, data: &[u8]) -> Result<AugmentationData
             // LSDA pointer is specified by the pointer encoding used.
             b'L' => {
                 trace!("L");
-                let encoding = read_u8(data)?;
-                aug_data.lsda_pointer_encoding = Some(Encoding(encoding));
+                let encoding = Encoding(read_u8(data)?);
+                aug_data.lsda_pointer_encoding = Some(encoding).filter(|e| !e.is_omit());
             }
             // If present, it indicates the presence of two arguments in the Augmentation Data of
             // the CIE. The first argument is 1-byte and represents the pointer encoding
@@ -726,17 +1275,24 @@ fn parse_augmentation_data(string: &str, data: &[u8]) -> Result<AugmentationData
             b'P' => {
                 trace!("P");
                 let encoding = Encoding(read_u8(data)?);
-                let (read_size, value) = unsafe { read_encoded(data.0.as_ptr(), encoding, None) };
-                data.0 = &data.0[read_size..];
-                aug_data.personality = Some(value);
+                // An omitted personality routine has no pointer.
+                if !encoding.is_omit() {
+                    let address = section.address_of(data.0);
+                    let pointer = read_pointer(data, address, encoding, &section.bases)?;
+                    aug_data.personality = Some(pointer);
+                }
             }
             // If present, The Augmentation Data shall include a 1 byte argument that represents the
             // pointer encoding for the address pointers used in the FDE.
             b'R' => {
-                let encoding = read_u8(data)?;
-                aug_data.pointer_encoding = Some(Encoding(encoding));
+                let encoding = Encoding(read_u8(data)?);
+                aug_data.pointer_encoding = Some(encoding).filter(|e| !e.is_omit());
             }
-            _ => return Err(Error(format!("invalid augmentation code: {code}"))),
+            // The GNU extensions below have no augmentation data.
+            b'S' => aug_data.signal_frame = true,
+            b'B' => aug_data.branch_target_protection = true,
+            b'G' => aug_data.memory_tagged = true,
+            _ => return Err(Error::UnknownAugmentation(code)),
         }
     }
 
@@ -744,31 +1300,61 @@ fn parse_augmentation_data(string: &str, data: &[u8]) -> Result<AugmentationData
 }
 
 pub(super) struct InstructionParser<'a> {
-    data: core::slice::Iter<'a, u8>,
+    data: Cursor<'a>,
+    instructions: &'a [u8],
+    /// The address of the instructions when their section is loaded.
+    address: usize,
+    bases: Bases,
 }
 
 impl<'a> InstructionParser<'a> {
-    pub(super) fn new(data: &'a [u8]) -> Self {
-        Self { data: data.iter() }
+    pub(super) fn new(instructions: &'a [u8], address: usize, bases: Bases) -> Self {
+        Self {
+            data: Cursor(instructions),
+            instructions,
+            address,
+            bases,
+        }
     }
 
     fn advance(&mut self) -> Option<u8> {
-        self.data.next().copied()
+        let (&first, rest) = self.data.0.split_first()?;
+        self.data.0 = rest;
+        Some(first)
     }
 
-    fn uleb128(&mut self) -> usize {
-        let mut acc = 0_usize;
-        loop {
-            let b = self.advance().unwrap();
-            let most = b & 0b1000_0000;
-            let rest = b & 0b0111_1111;
+    fn uleb128(&mut self) -> Result<usize> {
+        read_uleb128(&mut self.data)
+    }
 
-            acc = (acc << 7) | (rest as usize);
+    fn sleb128(&mut self) -> Result<isize> {
+        read_ileb128(&mut self.data)
+    }
 
-            if most == 0 {
-                return acc;
-            }
-        }
+    fn u8(&mut self) -> Result<u8> {
+        read_u8(&mut self.data)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        read_u16(&mut self.data)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        read_u32(&mut self.data)
+    }
+
+    fn encoded(&mut self, encoding: Encoding) -> Result<usize> {
+        let address = self
+            .address
+            .wrapping_add(self.instructions.len() - self.data.0.len());
+        read_encoded(&mut self.data, address, encoding, &self.bases)
+    }
+
+    /// A DW_FORM_block: the length as an unsigned LEB128, followed by the
+    /// bytes.
+    fn block(&mut self) -> Result<&'a [u8]> {
+        let len = self.uleb128()?;
+        read_bytes(&mut self.data, len)
     }
 }
 
@@ -805,44 +1391,120 @@ const DW_CFA_hi_user: u8 = 0x3f;
 #[cfg(target_arch = "x86_64")]
 pub(crate) mod arch {
     pub(crate) const REG_FRAME_POINTER: usize = 6;
+    pub(crate) const REG_STACK_POINTER: usize = 7;
     pub(crate) const RETURN_ADDRESS: usize = 16;
+    /// The amount of DWARF registers we track rules for, the return address
+    /// is the last one.
+    pub(crate) const REGISTER_COUNT: usize = 17;
 }
 
+const DW_CFA_GNU_args_size: u8 = 0x2e;
+const DW_CFA_GNU_negative_offset_extended: u8 = 0x2f;
+
+/// How many rows `DW_CFA_remember_state` can save at once. Compilers only
+/// use it around epilogues, so it is very rare to see it nested at all.
+const STATE_STACK_SIZE: usize = 4;
+
+/// The implicit stack of `DW_CFA_remember_state` and `DW_CFA_restore_state`.
+/// It has a fixed size so we never have to allocate while unwinding.
 #[derive(Debug)]
-struct CfaState {
-    cfa_rule_register: usize,
-    cfa_rule_offset: usize,
-    register_states: [RegisterState; 32],
-    current_offset: usize,
-    target_offset: usize,
+struct StateStack<'a> {
+    rows: [UnwindRow<'a>; STATE_STACK_SIZE],
+    len: usize,
 }
 
-impl CfaState {
-    fn advance(&mut self, count: usize) -> ControlFlow<()> {
-        if self.current_offset + count > self.target_offset {
+impl<'a> StateStack<'a> {
+    fn new() -> Self {
+        Self {
+            rows: [UnwindRow::new(); STATE_STACK_SIZE],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, row: UnwindRow<'a>) -> Result<()> {
+        let slot = self
+            .rows
+            .get_mut(self.len)
+            .ok_or(Error::StateStackOverflow)?;
+        *slot = row;
+        self.len += 1;
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<UnwindRow<'a>> {
+        if self.len == 0 {
+            return Err(Error::StateStackUnderflow);
+        }
+        self.len -= 1;
+        Ok(self.rows[self.len])
+    }
+}
+
+#[derive(Debug)]
+struct CfaState<'a> {
+    row: UnwindRow<'a>,
+    state_stack: StateStack<'a>,
+    /// The register rules after the initial instructions of the CIE, which
+    /// `DW_CFA_restore` goes back to.
+    initial_registers: Option<[RegisterRule<'a>; arch::REGISTER_COUNT]>,
+    location: usize,
+    target_location: usize,
+}
+
+impl<'a> CfaState<'a> {
+    fn set_location(&mut self, location: usize) -> ControlFlow<()> {
+        if location > self.target_location {
             ControlFlow::Break(())
         } else {
-            self.current_offset += count;
+            self.location = location;
             ControlFlow::Continue(())
         }
     }
-}
 
-#[derive(Clone, Copy, Debug)]
-enum RegisterState {
-    Undefined,
-    SameValue,
-    Offset(isize),
-    ValOffset(isize),
-    Register(usize),
-}
+    fn set_register(&mut self, register: usize, rule: RegisterRule<'a>) {
+        match self.row.registers.get_mut(register) {
+            Some(slot) => *slot = rule,
+            None => trace!(?register, "ignoring rule for untracked register"),
+        }
+    }
+
+    fn restore_register(&mut self, register: usize) -> Result<()> {
+        let initial = self
+            .initial_registers
+            .ok_or(Error::InvalidInstruction(DW_CFA_restore_hi << 6))?;
+        if let Some(&rule) = initial.get(register) {
+            self.set_register(register, rule);
+        }
+        Ok(())
+    }
 
-const DATA_ALIGNMENT_FACTOR: usize = 1;
+    fn set_cfa_offset(&mut self, new_offset: isize) -> Result<()> {
+        match &mut self.row.cfa {
+            CfaRule::RegisterOffset { offset, .. } => {
+                *offset = new_offset;
+                Ok(())
+            }
+            CfaRule::Expression(_) => Err(Error::InvalidInstruction(DW_CFA_def_cfa_offset)),
+        }
+    }
+}
 
-fn process_instruction_cfa_inner(ins: &mut InstructionParser, cfa: &mut CfaState, cie: &Cie<'_>) {
+fn process_instruction_cfa_inner<'a>(
+    ins: &mut InstructionParser<'a>,
+    cfa: &mut CfaState<'a>,
+    cie: &Cie<'_>,
+) -> Result<ControlFlow<()>> {
     let span = tracing::info_span!("process_instruction_cfa_inner");
     let _guard = span.enter();
 
+    let advance = |cfa: &mut CfaState<'a>, delta: usize| {
+        let location = delta
+            .checked_mul(cie.code_alignment_factor)
+            .and_then(|delta| cfa.location.checked_add(delta))
+            .ok_or(Error::Overflow)?;
+        Ok(cfa.set_location(location))
+    };
+
     while let Some(b) = ins.advance() {
         match b >> 6 {
             DW_CFA_advance_loc_hi => {
@@ -850,95 +1512,309 @@ fn process_instruction_cfa_inner(ins: &mut InstructionParser, cfa: &mut CfaState
 
                 trace!(?delta, "DW_CFA_advance_loc");
 
-                match cfa.advance(delta as usize) {
-                    ControlFlow::Continue(()) => {}
-                    ControlFlow::Break(()) => return,
+                if advance(cfa, delta as usize)?.is_break() {
+                    return Ok(ControlFlow::Break(()));
                 }
             }
             DW_CFA_offset_hi => {
                 let register = (b << 2) >> 2;
-                let factored_offset = ins.uleb128();
+                let factored_offset = ins.uleb128()?;
 
                 trace!(?register, ?factored_offset, "DW_CFA_offset");
 
-                cfa.register_states[register as usize] =
-                    RegisterState::Offset(factored_offset as isize * cie.data_alignment_factor);
+                cfa.set_register(
+                    register as usize,
+                    RegisterRule::Offset(cie.data_offset(factored_offset as i128)?),
+                );
             }
             DW_CFA_restore_hi => {
                 let register = (b << 2) >> 2;
-                todo!()
+                trace!(?register, "DW_CFA_restore");
+                cfa.restore_register(register as usize)?;
             }
             _ => match b {
                 DW_CFA_nop => {
                     trace!("DW_CFA_nop");
                 }
-                DW_CFA_set_loc => todo!(),
-                DW_CFA_advance_loc1 => todo!(),
-                DW_CFA_advance_loc2 => todo!(),
-                DW_CFA_advance_loc4 => todo!(),
+                DW_CFA_set_loc => {
+                    // The address has the same encoding as the FDE addresses.
+                    let encoding = match cie.augmentation {
+                        Some(augmentation) => augmentation
+                            .pointer_encoding
+                            .unwrap_or(Encoding(ValueFormat::DW_EH_PE_absptr as u8)),
+                        // Plain target addresses. In `.eh_frame` those are
+                        // native pointers without a segment selector.
+                        None => {
+                            read_bytes(&mut ins.data, cie.segment_selector_size.into())?;
+                            cie.target_address_encoding()?
+                        }
+                    };
+                    let location = ins.encoded(encoding)?;
+                    trace!("DW_CFA_set_loc {location:x}");
+                    if cfa.set_location(location).is_break() {
+                        return Ok(ControlFlow::Break(()));
+                    }
+                }
+                DW_CFA_advance_loc1 => {
+                    let delta = ins.u8()?;
+                    trace!(?delta, "DW_CFA_advance_loc1");
+                    if advance(cfa, delta as usize)?.is_break() {
+                        return Ok(ControlFlow::Break(()));
+                    }
+                }
+                DW_CFA_advance_loc2 => {
+                    let delta = ins.u16()?;
+                    trace!(?delta, "DW_CFA_advance_loc2");
+                    if advance(cfa, delta as usize)?.is_break() {
+                        return Ok(ControlFlow::Break(()));
+                    }
+                }
+                DW_CFA_advance_loc4 => {
+                    let delta = ins.u32()?;
+                    trace!(?delta, "DW_CFA_advance_loc4");
+                    if advance(cfa, delta as usize)?.is_break() {
+                        return Ok(ControlFlow::Break(()));
+                    }
+                }
                 DW_CFA_offset_extended => {
-                    let register_number = ins.uleb128();
-                    let factored_offset = ins.uleb128();
-                    todo!()
+                    let register = ins.uleb128()?;
+                    let factored_offset = ins.uleb128()?;
+                    trace!(?register, ?factored_offset, "DW_CFA_offset_extended");
+                    cfa.set_register(
+                        register,
+                        RegisterRule::Offset(cie.data_offset(factored_offset as i128)?),
+                    );
+                }
+                DW_CFA_restore_extended => {
+                    let register = ins.uleb128()?;
+                    trace!(?register, "DW_CFA_restore_extended");
+                    cfa.restore_register(register)?;
+                }
+                DW_CFA_undefined => {
+                    let register = ins.uleb128()?;
+                    trace!(?register, "DW_CFA_undefined");
+                    cfa.set_register(register, RegisterRule::Undefined);
+                }
+                DW_CFA_same_value => {
+                    let register = ins.uleb128()?;
+                    trace!(?register, "DW_CFA_same_value");
+                    cfa.set_register(register, RegisterRule::SameValue);
+                }
+                DW_CFA_register => {
+                    let register = ins.uleb128()?;
+                    let from_register = ins.uleb128()?;
+                    trace!(?register, ?from_register, "DW_CFA_register");
+                    cfa.set_register(register, RegisterRule::Register(from_register as u16));
+                }
+                DW_CFA_remember_state => {
+                    trace!("DW_CFA_remember_state");
+                    cfa.state_stack.push(cfa.row)?;
+                }
+                DW_CFA_restore_state => {
+                    trace!("DW_CFA_restore_state");
+                    cfa.row = cfa.state_stack.pop()?;
                 }
-                DW_CFA_restore_extended => todo!(),
-                DW_CFA_undefined => todo!(),
-                DW_CFA_same_value => todo!(),
-                DW_CFA_register => todo!(),
-                DW_CFA_remember_state => todo!(),
-                DW_CFA_restore_state => todo!(),
                 DW_CFA_def_cfa => {
-                    let register = ins.uleb128();
-                    let offset = ins.uleb128();
-                    trace!(?register, ?offset, "def_cfa");
-                    cfa.cfa_rule_register = register;
-                    cfa.cfa_rule_offset = offset;
+                    let register = ins.uleb128()?;
+                    let offset = ins.uleb128()?;
+                    trace!(?register, ?offset, "DW_CFA_def_cfa");
+                    cfa.row.cfa = CfaRule::RegisterOffset {
+                        register: register as u16,
+                        offset: offset as isize,
+                    };
                 }
                 DW_CFA_def_cfa_register => {
-                    let register = ins.uleb128();
+                    let register = ins.uleb128()?;
                     trace!(?register, "DW_CFA_def_cfa_register");
-                    cfa.cfa_rule_register = register;
+                    match &mut cfa.row.cfa {
+                        CfaRule::RegisterOffset { register: r, .. } => *r = register as u16,
+                        CfaRule::Expression(_) => {
+                            return Err(Error::InvalidInstruction(DW_CFA_def_cfa_register))
+                        }
+                    }
                 }
                 DW_CFA_def_cfa_offset => {
-                    let offset = ins.uleb128();
+                    let offset = ins.uleb128()?;
                     trace!(?offset, "DW_CFA_def_cfa_offset");
-                    cfa.cfa_rule_offset = offset;
+                    cfa.set_cfa_offset(offset as isize)?;
+                }
+                DW_CFA_def_cfa_expression => {
+                    let expr = ins.block()?;
+                    trace!(?expr, "DW_CFA_def_cfa_expression");
+                    cfa.row.cfa = CfaRule::Expression(Expr(expr));
+                }
+                DW_CFA_expression => {
+                    let register = ins.uleb128()?;
+                    let expr = ins.block()?;
+                    trace!(?register, ?expr, "DW_CFA_expression");
+                    cfa.set_register(register, RegisterRule::Expression(Expr(expr)));
+                }
+                DW_CFA_offset_extended_sf => {
+                    let register = ins.uleb128()?;
+                    let factored_offset = ins.sleb128()?;
+                    trace!(?register, ?factored_offset, "DW_CFA_offset_extended_sf");
+                    cfa.set_register(
+                        register,
+                        RegisterRule::Offset(cie.data_offset(factored_offset as i128)?),
+                    );
+                }
+                DW_CFA_def_cfa_sf => {
+                    let register = ins.uleb128()?;
+                    let factored_offset = ins.sleb128()?;
+                    trace!(?register, ?factored_offset, "DW_CFA_def_cfa_sf");
+                    cfa.row.cfa = CfaRule::RegisterOffset {
+                        register: register as u16,
+                        offset: cie.data_offset(factored_offset as i128)?,
+                    };
+                }
+                DW_CFA_def_cfa_offset_sf => {
+                    let factored_offset = ins.sleb128()?;
+                    trace!(?factored_offset, "DW_CFA_def_cfa_offset_sf");
+                    cfa.set_cfa_offset(cie.data_offset(factored_offset as i128)?)?;
+                }
+                DW_CFA_val_offset => {
+                    let register = ins.uleb128()?;
+                    let factored_offset = ins.uleb128()?;
+                    trace!(?register, ?factored_offset, "DW_CFA_val_offset");
+                    cfa.set_register(
+                        register,
+                        RegisterRule::ValOffset(cie.data_offset(factored_offset as i128)?),
+                    );
                 }
-                DW_CFA_def_cfa_expression => todo!(),
-                DW_CFA_expression => todo!(),
-                DW_CFA_offset_extended_sf => todo!(),
-                DW_CFA_def_cfa_sf => todo!(),
-                DW_CFA_def_cfa_offset_sf => todo!(),
-                DW_CFA_val_offset => todo!(),
-                DW_CFA_val_offset_sf => todo!(),
-                DW_CFA_val_expression => todo!(),
-                _ => todo!(),
+                DW_CFA_val_offset_sf => {
+                    let register = ins.uleb128()?;
+                    let factored_offset = ins.sleb128()?;
+                    trace!(?register, ?factored_offset, "DW_CFA_val_offset_sf");
+                    cfa.set_register(
+                        register,
+                        RegisterRule::ValOffset(cie.data_offset(factored_offset as i128)?),
+                    );
+                }
+                DW_CFA_val_expression => {
+                    let register = ins.uleb128()?;
+                    let expr = ins.block()?;
+                    trace!(?register, ?expr, "DW_CFA_val_expression");
+                    cfa.set_register(register, RegisterRule::ValExpression(Expr(expr)));
+                }
+                DW_CFA_GNU_args_size => {
+                    let size = ins.uleb128()?;
+                    trace!(?size, "DW_CFA_GNU_args_size");
+                    cfa.row.args_size = size;
+                }
+                DW_CFA_GNU_negative_offset_extended => {
+                    let register = ins.uleb128()?;
+                    let factored_offset = ins.uleb128()?;
+                    trace!(
+                        ?register,
+                        ?factored_offset,
+                        "DW_CFA_GNU_negative_offset_extended"
+                    );
+                    cfa.set_register(
+                        register,
+                        RegisterRule::Offset(cie.data_offset(-(factored_offset as i128))?),
+                    );
+                }
+                _ => return Err(Error::UnknownOpcode(b)),
             },
         }
     }
+
+    Ok(ControlFlow::Continue(()))
 }
 
-pub(crate) fn process_instructions_cfa(
-    cie: &Cie<'_>,
-    initial_instructions: &[u8],
-    instructions: &[u8],
-    to_offset: usize,
-) {
-    debug!("process instructions: {initial_instructions:x?}, {instructions:x?}");
+/// Executes the CFI instructions of the FDE up to `pc`, returning the row of
+/// the table for that location.
+pub(crate) fn process_instructions_cfa<'a>(
+    fde: &ParsedFde<'a>,
+    pc: usize,
+) -> Result<UnwindRow<'a>> {
+    debug!(
+        "process instructions: {:x?}, {:x?}",
+        fde.initial_instructions, fde.instructions
+    );
 
     let mut cfa = CfaState {
-        cfa_rule_offset: 0,
-        cfa_rule_register: 0,
-        register_states: [RegisterState::Undefined; 32],
-        current_offset: 0,
-        target_offset: to_offset,
+        row: UnwindRow::new(),
+        state_stack: StateStack::new(),
+        initial_registers: None,
+        location: fde.initial_location,
+        target_location: pc,
     };
 
-    let mut ins = InstructionParser::new(initial_instructions);
-    process_instruction_cfa_inner(&mut ins, &mut cfa, cie);
+    let mut ins = InstructionParser::new(
+        fde.initial_instructions,
+        fde.cie.initial_instructions_address,
+        fde.bases,
+    );
+    if process_instruction_cfa_inner(&mut ins, &mut cfa, &fde.cie)?.is_continue() {
+        cfa.initial_registers = Some(cfa.row.registers);
+
+        let mut ins = InstructionParser::new(fde.instructions, fde.instructions_address, fde.bases);
+        // Whether we stopped early or ran out of instructions doesn't matter, the
+        // current row covers our location either way.
+        let _ = process_instruction_cfa_inner(&mut ins, &mut cfa, &fde.cie)?;
+    }
+
+    trace!("{:?}", cfa.row);
+    Ok(cfa.row)
+}
+
+/// Applies the unwind row of a frame to its registers, producing the registers
+/// of its caller. Saved registers are read from the stack through `memory`.
+///
+/// Registers with an undefined rule are set to zero. For the return address,
+/// this means that we have reached the end of the stack.
+pub(crate) fn step(
+    row: &UnwindRow<'_>,
+    cie: &Cie<'_>,
+    regs: &Context,
+    memory: &impl Memory,
+) -> Result<Context> {
+    let register = |register: usize| {
+        regs.registers
+            .get(register)
+            .copied()
+            .ok_or(Error::InvalidRegister(register))
+    };
+
+    let cfa = match row.cfa {
+        CfaRule::RegisterOffset {
+            register: cfa_register,
+            offset,
+        } => register(cfa_register as usize)?.wrapping_add_signed(offset),
+        CfaRule::Expression(expr) => expr::evaluate(expr, regs, memory, None)?,
+    };
+    trace!("cfa={cfa:x}");
+
+    let mut caller = Context::new();
+    for (i, rule) in row.registers.iter().enumerate() {
+        caller.registers[i] = match *rule {
+            RegisterRule::Undefined => 0,
+            RegisterRule::SameValue => regs.registers[i],
+            RegisterRule::Offset(offset) => {
+                memory.read(cfa.wrapping_add_signed(offset), size_of::<usize>())?
+            }
+            RegisterRule::ValOffset(offset) => cfa.wrapping_add_signed(offset),
+            RegisterRule::Register(other) => register(other as usize)?,
+            RegisterRule::Expression(expr) => {
+                let addr = expr::evaluate(expr, regs, memory, Some(cfa))?;
+                memory.read(addr, size_of::<usize>())?
+            }
+            RegisterRule::ValExpression(expr) => expr::evaluate(expr, regs, memory, Some(cfa))?,
+            RegisterRule::Architectural => return Err(Error::UnsupportedRule),
+        };
+    }
+
+    // By definition, the CFA is the value of the stack pointer in the caller.
+    if row.registers[arch::REG_STACK_POINTER] == RegisterRule::SameValue {
+        caller.registers[arch::REG_STACK_POINTER] = cfa;
+    }
 
-    let mut ins = InstructionParser::new(instructions);
-    process_instruction_cfa_inner(&mut ins, &mut cfa, cie);
+    caller.registers[arch::RETURN_ADDRESS] = caller
+        .registers
+        .get(cie.return_address_register)
+        .copied()
+        .ok_or(Error::InvalidRegister(cie.return_address_register))?;
 
-    trace!("{cfa:?}");
+    Ok(caller)
 }
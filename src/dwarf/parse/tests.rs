@@ -1,38 +1,176 @@
-use crate::dwarf::parse::{AugmentationData, Cie, Encoding, ValueApplication, ValueFormat};
+use crate::{
+    arch::Context,
+    dwarf::{
+        expr::ProcessMemory,
+        parse::{
+            arch, AugmentationData, Bases, CfaRule, Cie, Cursor, DebugFrame, EhFrame, Encoding,
+            Error, ParsedFde, Pointer, RegisterRule, ValueApplication, ValueFormat,
+        },
+    },
+};
+
+fn simple_cie() -> Cie<'static> {
+    Cie {
+        augmentation: None,
+        augmentation_string: "",
+        address_size: 8,
+        segment_selector_size: 0,
+        code_alignment_factor: 1,
+        data_alignment_factor: -8,
+        return_address_register: 16,
+        initial_instructions: &[0xc, 7, 8, 0x90, 1, 0, 0],
+        initial_instructions_address: 0,
+    }
+}
+
+const FUNCTION: usize = 0x1000;
+
+/// Assembles an `.eh_frame` section for tests: one CIE with `DW_CFA_def_cfa:
+/// RSP +8` and `DW_CFA_offset: RIP -8` as its initial instructions, the FDEs
+/// using it and the terminator. Entries are padded to 4 bytes.
+pub(crate) struct CfiBuilder {
+    pub(crate) dwarf64: bool,
+    pub(crate) version: u8,
+    /// Only `R` and the augmentations without data are supported.
+    pub(crate) augmentation: &'static str,
+    pub(crate) pointer_encoding: u8,
+}
+
+impl Default for CfiBuilder {
+    fn default() -> Self {
+        CfiBuilder {
+            dwarf64: false,
+            version: 1,
+            augmentation: "zR",
+            pointer_encoding: ValueFormat::DW_EH_PE_udata8 as u8,
+        }
+    }
+}
+
+impl CfiBuilder {
+    /// The section with an FDE for every `(initial_location, address_range)`.
+    /// The values are written as they are in the pointer encoding, so a
+    /// PC-relative initial location is relative to its own field.
+    pub(crate) fn build(&self, fdes: &[(i64, u64)]) -> Vec<u8> {
+        let mut section = Vec::new();
+
+        let mut cie = vec![self.version];
+        cie.extend_from_slice(self.augmentation.as_bytes());
+        cie.push(0);
+        if self.version >= 4 {
+            cie.extend_from_slice(&[8, 0]); // address and segment selector size
+        }
+        cie.extend_from_slice(&[1, 0x78]); // code and data alignment factor
+        if self.version == 1 {
+            cie.push(16);
+        } else {
+            // A padded ULEB128, which can't be mistaken for a ubyte.
+            cie.extend_from_slice(&[0x90, 0x00]);
+        }
+        if self.augmentation.starts_with('z') {
+            let mut data = vec![];
+            for code in self.augmentation.bytes().skip(1) {
+                match code {
+                    b'R' => data.push(self.pointer_encoding),
+                    b'S' | b'B' | b'G' => {}
+                    _ => unimplemented!("augmentation {:?}", code as char),
+                }
+            }
+            cie.push(data.len() as u8);
+            cie.extend_from_slice(&data);
+        }
+        cie.extend_from_slice(&[0x0c, 0x07, 0x08, 0x90, 0x01]);
+        self.push_entry(&mut section, 0, &cie);
+
+        let size = Encoding(self.pointer_encoding).size().unwrap();
+        for &(initial_location, address_range) in fdes {
+            let mut fde = initial_location.to_le_bytes()[..size].to_vec();
+            fde.extend_from_slice(&address_range.to_le_bytes()[..size]);
+            if self.augmentation.starts_with('z') {
+                fde.push(0); // augmentation data length
+            }
+            let cie_pointer = section.len() + if self.dwarf64 { 12 } else { 4 };
+            self.push_entry(&mut section, cie_pointer as u32, &fde);
+        }
+
+        section.extend_from_slice(&[0; 4]);
+        section
+    }
+
+    fn push_entry(&self, section: &mut Vec<u8>, id: u32, data: &[u8]) {
+        let header = if self.dwarf64 { 12 } else { 4 };
+        let padding = (header + 4 + data.len()).wrapping_neg() % 4;
+        let len = 4 + data.len() + padding;
+        if self.dwarf64 {
+            section.extend_from_slice(&[0xff; 4]);
+            section.extend_from_slice(&(len as u64).to_le_bytes());
+        } else {
+            section.extend_from_slice(&(len as u32).to_le_bytes());
+        }
+        section.extend_from_slice(&id.to_le_bytes());
+        section.extend_from_slice(data);
+        section.resize(section.len() + padding, 0);
+    }
+}
+
+fn simple_fde(instructions: &[u8]) -> ParsedFde<'_> {
+    let cie = simple_cie();
+    ParsedFde {
+        address: 0,
+        initial_location: FUNCTION,
+        address_range: 0x100,
+        initial_instructions: cie.initial_instructions,
+        instructions,
+        instructions_address: 0,
+        bases: Bases {
+            func: FUNCTION,
+            ..Bases::default()
+        },
+        cie,
+        lsda: None,
+    }
+}
 
 #[test]
 fn parse_simple_cie() {
-    /*
-        #[rustfmt::skip]
-        let data = [
-            0x14, 0, 0, 0,
-            0, 0, 0, 0, 1,
-            0x7a, 0x52, 0, 1,
-            0x78, 0x10, 1,
-            0x1b, 0xc, 7, 8,
-            0x90, 1, 0, 0,
-        ];
-
-        let cie = unsafe { super::parse_frame_info(data.as_ptr()) }.unwrap().0;
+    #[rustfmt::skip]
+    let data = [
+        0x14, 0, 0, 0,
+        0, 0, 0, 0, 1,
+        0x7a, 0x52, 0, 1,
+        0x78, 0x10, 1,
+        0x1b, 0xc, 7, 8,
+        0x90, 1, 0, 0,
+    ];
 
-        assert_eq!(
-            cie,
-            Cie {
-                augmentation: Some(AugmentationData {
-                    lsda_pointer_encoding: None,
-                    pointer_encoding: Some(Encoding(
-                        (ValueApplication::DW_EH_PE_pcrel as u8) | (ValueFormat::DW_EH_PE_sdata4 as u8)
-                    )),
-                    personality: None
-                }),
-                augmentation_string: "meow",
-                code_alignment_factor: 1,
-                data_alignment_factor: -8,
-                return_address_register: 16,
-                initial_instructions: &[0xc, 7, 8, 0x90, 1, 0, 0]
-            }
-        );
-    */
+    let eh_frame = EhFrame::new(&data, 0x4000);
+    let head = super::parse_frame_head(&eh_frame.0, 0).unwrap().unwrap();
+    assert_eq!(head.id, 0);
+    let cie = super::parse_cie(&mut Cursor(head.data), &eh_frame.0).unwrap();
+
+    assert_eq!(
+        cie,
+        Cie {
+            augmentation: Some(AugmentationData {
+                lsda_pointer_encoding: None,
+                pointer_encoding: Some(Encoding(
+                    (ValueApplication::DW_EH_PE_pcrel as u8) | (ValueFormat::DW_EH_PE_sdata4 as u8)
+                )),
+                personality: None,
+                signal_frame: false,
+                branch_target_protection: false,
+                memory_tagged: false,
+            }),
+            augmentation_string: "zR",
+            address_size: 8,
+            segment_selector_size: 0,
+            code_alignment_factor: 1,
+            data_alignment_factor: -8,
+            return_address_register: 16,
+            initial_instructions: &[0xc, 7, 8, 0x90, 1, 0, 0],
+            initial_instructions_address: 0x4011,
+        }
+    );
     // llvm-dwarfdump output:
     /*
     00000000 00000014 00000000 CIE
@@ -52,3 +190,697 @@ fn parse_simple_cie() {
     CFA=RSP+8: RIP=[CFA-8]
     */
 }
+
+#[test]
+fn parse_fde_with_lsda() {
+    let udata8 = Encoding(ValueFormat::DW_EH_PE_udata8 as u8);
+    let cie = Cie {
+        augmentation: Some(AugmentationData {
+            lsda_pointer_encoding: Some(udata8),
+            pointer_encoding: Some(udata8),
+            personality: Some(Pointer::Direct(0x5000)),
+            signal_frame: false,
+            branch_target_protection: false,
+            memory_tagged: false,
+        }),
+        augmentation_string: "zPLR",
+        ..simple_cie()
+    };
+
+    #[rustfmt::skip]
+    let data = [
+        0x00, 0x10, 0, 0, 0, 0, 0, 0, // initial location
+        0x00, 0x01, 0, 0, 0, 0, 0, 0, // address range
+        8,                            // augmentation length
+        0x34, 0x12, 0, 0, 0, 0, 0, 0, // LSDA
+        0x41,                         // DW_CFA_advance_loc: 1
+    ];
+    let eh_frame = EhFrame::new(&data, 0x2008);
+    let fde = super::parse_fde(&mut Cursor(&data), &eh_frame.0, 0x2000, cie).unwrap();
+
+    assert_eq!(fde.address, 0x2000);
+    assert_eq!(fde.initial_location, FUNCTION);
+    assert_eq!(fde.address_range, 0x100);
+    assert_eq!(fde.lsda, Some(Pointer::Direct(0x1234)));
+    assert_eq!(fde.instructions, &[0x41]);
+    assert_eq!(fde.instructions_address, 0x2008 + 25);
+}
+
+#[test]
+fn parse_fde_with_zero_lsda() {
+    let cie = Cie {
+        augmentation: Some(AugmentationData {
+            lsda_pointer_encoding: Some(Encoding(0x9b)),
+            pointer_encoding: Some(Encoding(ValueFormat::DW_EH_PE_udata8 as u8)),
+            personality: None,
+            signal_frame: false,
+            branch_target_protection: false,
+            memory_tagged: false,
+        }),
+        augmentation_string: "zLR",
+        ..simple_cie()
+    };
+
+    #[rustfmt::skip]
+    let data = [
+        0x00, 0x10, 0, 0, 0, 0, 0, 0, // initial location
+        0x00, 0x01, 0, 0, 0, 0, 0, 0, // address range
+        4,                            // augmentation length
+        0, 0, 0, 0,                   // LSDA: indirect pcrel sdata4 0
+    ];
+    let eh_frame = EhFrame::new(&data, 0x2008);
+    let fde = super::parse_fde(&mut Cursor(&data), &eh_frame.0, 0x2000, cie).unwrap();
+
+    // Not the address of the LSDA field, and nothing to read through.
+    assert_eq!(fde.lsda, Some(Pointer::Direct(0)));
+}
+
+#[test]
+fn process_function_prologue() {
+    #[rustfmt::skip]
+    let instructions = [
+        0x41,       // DW_CFA_advance_loc: 1
+        0x0e, 0x10, // DW_CFA_def_cfa_offset: +16
+        0x86, 0x02, // DW_CFA_offset: RBP -16
+        0x43,       // DW_CFA_advance_loc: 3
+        0x0d, 0x06, // DW_CFA_def_cfa_register: RBP
+    ];
+
+    let row = super::process_instructions_cfa(&simple_fde(&instructions), FUNCTION).unwrap();
+    assert_eq!(
+        row.cfa,
+        CfaRule::RegisterOffset {
+            register: 7,
+            offset: 8
+        }
+    );
+    assert_eq!(row.registers[16], RegisterRule::Offset(-8));
+    assert_eq!(row.registers[6], RegisterRule::SameValue);
+
+    let row = super::process_instructions_cfa(&simple_fde(&instructions), FUNCTION + 3).unwrap();
+    assert_eq!(
+        row.cfa,
+        CfaRule::RegisterOffset {
+            register: 7,
+            offset: 16
+        }
+    );
+    assert_eq!(row.registers[6], RegisterRule::Offset(-16));
+
+    let row = super::process_instructions_cfa(&simple_fde(&instructions), FUNCTION + 4).unwrap();
+    assert_eq!(
+        row.cfa,
+        CfaRule::RegisterOffset {
+            register: 6,
+            offset: 16
+        }
+    );
+}
+
+#[test]
+fn step_function_prologue() {
+    let cie = simple_cie();
+    let instructions = [0x41, 0x0e, 0x10, 0x86, 0x02];
+    let row = super::process_instructions_cfa(&simple_fde(&instructions), FUNCTION + 1).unwrap();
+
+    // The stack right after `push rbp`.
+    let stack: [usize; 2] = [0xbbbb, 0x1234];
+    let mut regs = Context::new();
+    regs.registers[arch::REG_FRAME_POINTER] = 0xaaaa;
+    regs.registers[arch::REG_STACK_POINTER] = stack.as_ptr().expose_provenance();
+    regs.registers[3] = 0xcccc;
+
+    let memory = unsafe { ProcessMemory::new() };
+    let caller = super::step(&row, &cie, &regs, &memory).unwrap();
+
+    assert_eq!(caller.registers[arch::RETURN_ADDRESS], 0x1234);
+    assert_eq!(caller.registers[arch::REG_FRAME_POINTER], 0xbbbb);
+    assert_eq!(
+        caller.registers[arch::REG_STACK_POINTER],
+        stack.as_ptr().expose_provenance() + 16
+    );
+    assert_eq!(caller.registers[3], 0xcccc);
+}
+
+#[test]
+fn process_all_instructions() {
+    let mut cie = simple_cie();
+    cie.code_alignment_factor = 4;
+    #[rustfmt::skip]
+    let instructions = [
+        0x0c, 0x07, 0x10,       // DW_CFA_def_cfa: RSP +16
+        0x83, 0x02,             // DW_CFA_offset: RBX -16
+        0x11, 0x0c, 0x03,       // DW_CFA_offset_extended_sf: R12 -24
+        0x41,                   // DW_CFA_advance_loc: 1 (4 bytes)
+        0x02, 0x02,             // DW_CFA_advance_loc1: 2 (8 bytes)
+        0xc3,                   // DW_CFA_restore: RBX
+        0x09, 0x0d, 0x0e,       // DW_CFA_register: R13 in R14
+        0x14, 0x0f, 0x01,       // DW_CFA_val_offset: R15 -8
+        0x07, 0x00,             // DW_CFA_undefined: RAX
+        0x12, 0x06, 0x7e,       // DW_CFA_def_cfa_sf: RBP +16
+        0x2e, 0x08,             // DW_CFA_GNU_args_size: 8
+        0x03, 0x10, 0x00,       // DW_CFA_advance_loc2: 16 (64 bytes)
+        0x0f, 0x02, 0x77, 0x08, // DW_CFA_def_cfa_expression: DW_OP_breg7 +8
+    ];
+    let fde = ParsedFde {
+        cie,
+        ..simple_fde(&instructions)
+    };
+
+    let row = super::process_instructions_cfa(&fde, FUNCTION + 3).unwrap();
+    assert_eq!(
+        row.cfa,
+        CfaRule::RegisterOffset {
+            register: 7,
+            offset: 16
+        }
+    );
+    assert_eq!(row.registers[3], RegisterRule::Offset(-16));
+    assert_eq!(row.registers[12], RegisterRule::Offset(-24));
+    assert_eq!(row.args_size, 0);
+
+    let row = super::process_instructions_cfa(&fde, FUNCTION + 12).unwrap();
+    assert_eq!(
+        row.cfa,
+        CfaRule::RegisterOffset {
+            register: 6,
+            offset: 16
+        }
+    );
+    assert_eq!(row.registers[0], RegisterRule::Undefined);
+    assert_eq!(row.registers[3], RegisterRule::SameValue);
+    assert_eq!(row.registers[12], RegisterRule::Offset(-24));
+    assert_eq!(row.registers[13], RegisterRule::Register(14));
+    assert_eq!(row.registers[15], RegisterRule::ValOffset(-8));
+    assert_eq!(row.args_size, 8);
+
+    let row = super::process_instructions_cfa(&fde, FUNCTION + 76).unwrap();
+    assert_eq!(row.cfa, CfaRule::Expression(super::Expr(&[0x77, 0x08])));
+}
+
+#[test]
+fn remember_and_restore_state() {
+    #[rustfmt::skip]
+    let instructions = [
+        0x0e, 0x10, // DW_CFA_def_cfa_offset: +16
+        0x86, 0x02, // DW_CFA_offset: RBP -16
+        0x0a,       // DW_CFA_remember_state
+        0x41,       // DW_CFA_advance_loc: 1
+        0x0e, 0x08, // DW_CFA_def_cfa_offset: +8
+        0xc6,       // DW_CFA_restore: RBP
+        0x41,       // DW_CFA_advance_loc: 1
+        0x0b,       // DW_CFA_restore_state
+    ];
+    let fde = simple_fde(&instructions);
+
+    let row = super::process_instructions_cfa(&fde, FUNCTION + 1).unwrap();
+    assert_eq!(
+        row.cfa,
+        CfaRule::RegisterOffset {
+            register: 7,
+            offset: 8
+        }
+    );
+    assert_eq!(row.registers[6], RegisterRule::SameValue);
+
+    let row = super::process_instructions_cfa(&fde, FUNCTION + 2).unwrap();
+    assert_eq!(
+        row.cfa,
+        CfaRule::RegisterOffset {
+            register: 7,
+            offset: 16
+        }
+    );
+    assert_eq!(row.registers[6], RegisterRule::Offset(-16));
+}
+
+#[test]
+fn state_stack_errors() {
+    let fde = simple_fde(&[0x0b]);
+    assert_eq!(
+        super::process_instructions_cfa(&fde, FUNCTION),
+        Err(Error::StateStackUnderflow)
+    );
+
+    let fde = simple_fde(&[0x0a; super::STATE_STACK_SIZE + 1]);
+    assert_eq!(
+        super::process_instructions_cfa(&fde, FUNCTION),
+        Err(Error::StateStackOverflow)
+    );
+
+    let fde = simple_fde(&[0x0a; super::STATE_STACK_SIZE]);
+    assert!(super::process_instructions_cfa(&fde, FUNCTION).is_ok());
+}
+
+#[test]
+fn omitted_augmentation_pointers() {
+    let data = [0xff, 0xff, 0x04];
+    let aug = super::parse_augmentation_data("zPLR", &data, &EhFrame::new(&data, 0).0).unwrap();
+    assert_eq!(
+        aug,
+        AugmentationData {
+            lsda_pointer_encoding: None,
+            pointer_encoding: Some(Encoding(ValueFormat::DW_EH_PE_udata8 as u8)),
+            personality: None,
+            signal_frame: false,
+            branch_target_protection: false,
+            memory_tagged: false,
+        }
+    );
+    assert_eq!(format!("{:?}", Encoding(0xff)), "DW_EH_PE_omit");
+}
+
+#[test]
+fn gnu_augmentations() {
+    let data = [0x1b];
+    let aug = super::parse_augmentation_data("zRSBG", &data, &EhFrame::new(&data, 0).0).unwrap();
+    assert!(aug.signal_frame);
+    assert!(aug.branch_target_protection);
+    assert!(aug.memory_tagged);
+
+    assert_eq!(
+        super::parse_augmentation_data("zX", &[], &EhFrame::new(&[], 0).0),
+        Err(Error::UnknownAugmentation(b'X'))
+    );
+    // Augmentation data without the `z` that describes it.
+    assert_eq!(
+        super::parse_augmentation_data("Rz", &data, &EhFrame::new(&data, 0).0),
+        Err(Error::UnknownAugmentation(b'R'))
+    );
+    assert_eq!(
+        super::parse_augmentation_data("", &[], &EhFrame::new(&[], 0).0),
+        Err(Error::UnknownAugmentation(0))
+    );
+}
+
+#[test]
+fn signal_frame_cie() {
+    for version in [3, 4] {
+        let eh_frame = CfiBuilder {
+            version,
+            augmentation: "zRS",
+            pointer_encoding: 0x1b,
+            ..CfiBuilder::default()
+        }
+        .build(&[(-0x20, 0x100)]);
+
+        let fde = EhFrame::new(&eh_frame, 0x40_0000)
+            .find_fde(0x40_0010)
+            .unwrap()
+            .unwrap();
+        assert_eq!(fde.cie.augmentation_string, "zRS");
+        assert_eq!(fde.cie.address_size, 8);
+        assert_eq!(fde.cie.return_address_register, 16);
+        assert!(fde.cie.is_signal_frame());
+    }
+    assert!(!simple_cie().is_signal_frame());
+
+    // Version 4 CIEs have to match our pointers in `.eh_frame`.
+    let eh_frame = CfiBuilder {
+        version: 4,
+        ..CfiBuilder::default()
+    }
+    .build(&[(FUNCTION as i64, 0x100)]);
+    let mut address_size = eh_frame.clone();
+    address_size[12] = 4;
+    assert_eq!(
+        EhFrame::new(&address_size, 0).find_fde(FUNCTION).err(),
+        Some(Error::UnsupportedAddressSize(4))
+    );
+    let mut segment_selector = eh_frame.clone();
+    segment_selector[13] = 2;
+    assert_eq!(
+        EhFrame::new(&segment_selector, 0).find_fde(FUNCTION).err(),
+        Some(Error::UnsupportedSegmentSelectorSize(2))
+    );
+
+    let mut version_5 = eh_frame;
+    version_5[8] = 5;
+    assert_eq!(
+        EhFrame::new(&version_5, 0).find_fde(FUNCTION).err(),
+        Some(Error::UnsupportedVersion(5))
+    );
+}
+
+#[test]
+fn parse_64_bit_entries() {
+    let eh_frame = CfiBuilder {
+        dwarf64: true,
+        ..CfiBuilder::default()
+    }
+    .build(&[(FUNCTION as i64, 0x100)]);
+
+    let head = super::parse_frame_head(&EhFrame::new(&eh_frame, 0).0, 0)
+        .unwrap()
+        .unwrap();
+    assert_eq!(head.format, super::Format::Dwarf64);
+    assert_eq!(head.next, 32);
+
+    let fde = EhFrame::new(&eh_frame, 0x8000)
+        .find_fde(FUNCTION + 0x10)
+        .unwrap()
+        .unwrap();
+    assert_eq!(fde.address, 0x8000 + 32);
+    assert_eq!(fde.initial_location, FUNCTION);
+    assert_eq!(
+        fde.cie.initial_instructions,
+        &[0x0c, 0x07, 0x08, 0x90, 0x01, 0, 0]
+    );
+}
+
+#[test]
+fn eh_frame_from_bytes() {
+    let eh_frame = CfiBuilder {
+        pointer_encoding: 0x1b,
+        ..CfiBuilder::default()
+    }
+    .build(&[(-0x20, 0x100)]);
+
+    // The initial location is relative to where the section is loaded, not
+    // to where the bytes are.
+    let base = 0x40_0000;
+    let fde = EhFrame::new(&eh_frame, base)
+        .find_fde(0x40_0010)
+        .unwrap()
+        .unwrap();
+    assert_eq!(fde.address, 0x40_0018);
+    assert_eq!(fde.initial_location, 0x40_0000);
+    assert_eq!(fde.address_range, 0x100);
+    assert!(EhFrame::new(&eh_frame, base)
+        .find_fde(0x40_0100)
+        .unwrap()
+        .is_none());
+
+    // Cutting the section anywhere in the FDE is an error, not a crash.
+    for len in 25..44 {
+        assert_eq!(
+            EhFrame::new(&eh_frame[..len], base)
+                .find_fde(0x40_0010)
+                .err(),
+            Some(Error::Truncated),
+        );
+    }
+
+    // A CIE pointer before the start of the section.
+    let mut bad_cie_pointer = eh_frame.clone();
+    bad_cie_pointer[28] = 0x40;
+    assert_eq!(
+        EhFrame::new(&bad_cie_pointer, base)
+            .fde_at(24)
+            .map(|fde| fde.address),
+        Err(Error::InvalidCiePointer),
+    );
+    assert_eq!(
+        EhFrame::new(&eh_frame, base)
+            .fde_at(100)
+            .map(|fde| fde.address),
+        Err(Error::Truncated),
+    );
+}
+
+#[test]
+fn eh_frame_without_augmentation() {
+    // An empty augmentation string means native pointers and no augmentation
+    // data in the FDEs.
+    let eh_frame = CfiBuilder {
+        augmentation: "",
+        pointer_encoding: ValueFormat::DW_EH_PE_absptr as u8,
+        ..CfiBuilder::default()
+    }
+    .build(&[(0x1000, 0x100)]);
+
+    let fde = EhFrame::new(&eh_frame, 0)
+        .find_fde(0x1010)
+        .unwrap()
+        .unwrap();
+    assert!(fde.cie.augmentation.is_none());
+    assert_eq!(fde.initial_location, 0x1000);
+    assert_eq!(fde.address_range, 0x100);
+    assert_eq!(fde.lsda, None);
+    // Nothing but padding after the address range.
+    assert!(fde.instructions.iter().all(|&b| b == 0));
+}
+
+#[test]
+fn malformed_eh_frame() {
+    let eh_frame = CfiBuilder::default().build(&[(FUNCTION as i64, 0x100)]);
+
+    // Every corruption of a single byte is an error or garbage, never a panic.
+    for i in 0..eh_frame.len() {
+        for byte in [0x00, 0x01, 0x7f, 0x80, 0xff] {
+            let mut corrupted = eh_frame.clone();
+            corrupted[i] = byte;
+            let eh_frame = EhFrame::new(&corrupted, u64::MAX - 0x10);
+            if let Ok(Some(fde)) = eh_frame.find_fde(FUNCTION) {
+                let _ = super::process_instructions_cfa(&fde, FUNCTION + 0x10);
+            }
+        }
+    }
+
+    // A function at the very end of the address space.
+    let eh_frame = CfiBuilder::default().build(&[(-1, 0x100)]);
+    assert!(EhFrame::new(&eh_frame, 0)
+        .find_fde(FUNCTION)
+        .unwrap()
+        .is_none());
+
+    // LEB128 values with more continuation bytes than fit in a pointer.
+    let mut padded = [0x80; 12];
+    padded[11] = 0;
+    assert_eq!(super::read_uleb128(&mut Cursor(&padded)), Ok(0));
+    let mut too_big = [0xff; 12];
+    too_big[11] = 1;
+    assert_eq!(
+        super::read_uleb128(&mut Cursor(&too_big)),
+        Err(Error::Overflow)
+    );
+    let mut minus_one = [0xff; 12];
+    minus_one[11] = 0x7f;
+    assert_eq!(super::read_ileb128(&mut Cursor(&minus_one)), Ok(-1));
+    assert_eq!(
+        super::read_ileb128(&mut Cursor(&too_big)),
+        Err(Error::Overflow)
+    );
+    let min = [0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x7f];
+    assert_eq!(super::read_ileb128(&mut Cursor(&min)), Ok(isize::MIN));
+}
+
+#[test]
+fn overflowing_factors() {
+    let mut fde = simple_fde(&[0x41]); // DW_CFA_advance_loc: 1
+    fde.cie.code_alignment_factor = usize::MAX;
+    assert_eq!(
+        super::process_instructions_cfa(&fde, FUNCTION + 1),
+        Err(Error::Overflow)
+    );
+
+    // DW_CFA_offset: RBP, DW_CFA_GNU_negative_offset_extended: RBP
+    for instructions in [&[0x86, 0x02][..], &[0x2f, 0x06, 0x02]] {
+        let mut fde = simple_fde(instructions);
+        fde.cie.data_alignment_factor = isize::MIN;
+        assert_eq!(
+            super::process_instructions_cfa(&fde, FUNCTION),
+            Err(Error::Overflow)
+        );
+    }
+
+    // Instructions at the end of the address space, DW_CFA_set_loc.
+    let mut instructions = [0x01, 0, 0, 0, 0, 0, 0, 0, 0];
+    instructions[1..].copy_from_slice(&(FUNCTION as u64 + 0x10).to_le_bytes());
+    let mut fde = simple_fde(&instructions);
+    fde.instructions_address = usize::MAX;
+    assert!(super::process_instructions_cfa(&fde, FUNCTION).is_ok());
+
+    // An aligned pointer at the end of the address space.
+    let data = 0x1234usize.to_le_bytes();
+    let pointer = super::read_pointer(
+        &mut Cursor(&data),
+        usize::MAX - 7,
+        Encoding(0x50),
+        &Bases::default(),
+    );
+    assert_eq!(pointer, Ok(Pointer::Direct(0x1234)));
+}
+
+#[test]
+fn pointer_encodings() {
+    let bases = Bases {
+        text: 0x1_0000,
+        data: 0x2_0000,
+        func: 0x3_0000,
+    };
+    let read = |encoding: u8, data: &[u8], address: usize| {
+        let cursor = &mut Cursor(data);
+        let pointer = super::read_pointer(cursor, address, Encoding(encoding), &bases);
+        pointer.map(|pointer| (pointer, cursor.0.len()))
+    };
+
+    // absptr, native size
+    let data = 0x1234usize.to_le_bytes();
+    assert_eq!(read(0x00, &data, 0), Ok((Pointer::Direct(0x1234), 0)));
+    // uleb128 and sleb128
+    assert_eq!(
+        read(0x01, &[0xe5, 0x8e, 0x26, 1], 0),
+        Ok((Pointer::Direct(624485), 1))
+    );
+    assert_eq!(
+        read(0x09, &[0x7f], 0x10),
+        Ok((Pointer::Direct(-1isize as usize), 0))
+    );
+    // pcrel, textrel, datarel and funcrel sdata4
+    let data = (-4i32).to_le_bytes();
+    assert_eq!(read(0x1b, &data, 0x100), Ok((Pointer::Direct(0xfc), 0)));
+    assert_eq!(read(0x2b, &data, 0x100), Ok((Pointer::Direct(0xfffc), 0)));
+    assert_eq!(read(0x3b, &data, 0x100), Ok((Pointer::Direct(0x1_fffc), 0)));
+    assert_eq!(read(0x4b, &data, 0x100), Ok((Pointer::Direct(0x2_fffc), 0)));
+    // aligned skips the padding up to the next native pointer
+    let mut data = [0; 12];
+    data[4..].copy_from_slice(&0x5678usize.to_le_bytes());
+    assert_eq!(read(0x50, &data, 0x104), Ok((Pointer::Direct(0x5678), 0)));
+    // indirect|pcrel|sdata4, like personality routines in PIC code
+    let data = 8i32.to_le_bytes();
+    assert_eq!(read(0x9b, &data, 0x100), Ok((Pointer::Indirect(0x108), 0)));
+
+    assert_eq!(read(0xff, &data, 0), Err(Error::BadEncoding(0xff)));
+    assert_eq!(read(0x0d, &data, 0), Err(Error::BadEncoding(0x0d)));
+    assert_eq!(read(0x04, &data, 0), Err(Error::Truncated));
+    assert_eq!(read(0x01, &[0x80], 0), Err(Error::Truncated));
+}
+
+#[test]
+fn parse_debug_frame() {
+    #[rustfmt::skip]
+    let debug_frame: [u8; 106] = [
+        // CIE, version 4
+        20, 0, 0, 0,                  // length
+        0xff, 0xff, 0xff, 0xff,       // CIE id
+        4,                            // version
+        0,                            // augmentation
+        4,                            // address size
+        0,                            // segment selector size
+        1,                            // code alignment factor
+        0x7c,                         // data alignment factor: -4
+        8,                            // return address register
+        0x0c, 0x04, 0x04,             // DW_CFA_def_cfa: r4 +4
+        0x88, 0x01,                   // DW_CFA_offset: r8 -4
+        0, 0, 0, 0,                   // padding
+        // FDE of the version 4 CIE
+        12, 0, 0, 0,                  // length
+        0, 0, 0, 0,                   // CIE pointer
+        0, 0x10, 0, 0,                // initial location
+        0, 1, 0, 0,                   // address range
+        // CIE, version 3, 64-bit format
+        0xff, 0xff, 0xff, 0xff,       // 64-bit format
+        18, 0, 0, 0, 0, 0, 0, 0,      // length
+        0xff, 0xff, 0xff, 0xff,       // CIE id
+        0xff, 0xff, 0xff, 0xff,
+        3,                            // version
+        0,                            // augmentation
+        1,                            // code alignment factor
+        0x78,                         // data alignment factor: -8
+        16,                           // return address register
+        0x0c, 0x07, 0x08,             // DW_CFA_def_cfa: RSP +8
+        0x90, 0x01,                   // DW_CFA_offset: RIP -8
+        // FDE of the version 3 CIE
+        0xff, 0xff, 0xff, 0xff,       // 64-bit format
+        24, 0, 0, 0, 0, 0, 0, 0,      // length
+        40, 0, 0, 0, 0, 0, 0, 0,      // CIE pointer
+        0, 0x20, 0, 0, 0, 0, 0, 0,    // initial location
+        0, 1, 0, 0, 0, 0, 0, 0,       // address range
+    ];
+
+    let debug_frame = DebugFrame::new(&debug_frame, 8);
+
+    let fde = debug_frame.find_fde(0x1010).unwrap().unwrap();
+    assert_eq!(fde.address, 24);
+    assert_eq!(fde.initial_location, 0x1000);
+    assert_eq!(fde.address_range, 0x100);
+    assert_eq!(fde.cie.address_size, 4);
+    assert_eq!(fde.cie.return_address_register, 8);
+    assert_eq!(
+        fde.cie.initial_instructions,
+        &[0x0c, 4, 4, 0x88, 1, 0, 0, 0, 0]
+    );
+
+    let fde = debug_frame.find_fde(0x2010).unwrap().unwrap();
+    assert_eq!(fde.address, 70);
+    assert_eq!(fde.initial_location, 0x2000);
+    assert_eq!(fde.cie.address_size, 8);
+    assert_eq!(fde.cie.return_address_register, 16);
+
+    assert!(debug_frame.find_fde(0x3000).unwrap().is_none());
+    // CIEs are not FDEs, and `.eh_frame` CIE ids mean nothing here.
+    assert_eq!(
+        debug_frame.fde_at(0).map(|fde| fde.address),
+        Err(Error::InvalidFdePointer)
+    );
+}
+
+#[test]
+fn debug_frame_of_32_bit_target() {
+    #[rustfmt::skip]
+    let debug_frame: [u8; 44] = [
+        // CIE, version 1
+        16, 0, 0, 0,                  // length
+        0xff, 0xff, 0xff, 0xff,       // CIE id
+        1,                            // version
+        0,                            // augmentation
+        1,                            // code alignment factor
+        0x7c,                         // data alignment factor: -4
+        8,                            // return address register
+        0x0c, 0x04, 0x04,             // DW_CFA_def_cfa: r4 +4
+        0x88, 0x01,                   // DW_CFA_offset: r8 -4
+        0, 0,                         // padding
+        // FDE
+        20, 0, 0, 0,                  // length
+        0, 0, 0, 0,                   // CIE pointer
+        0, 0x10, 0, 0,                // initial location
+        0, 1, 0, 0,                   // address range
+        0x01, 0x10, 0x10, 0, 0,       // DW_CFA_set_loc: 0x1010
+        0x0e, 0x08,                   // DW_CFA_def_cfa_offset: +8
+        0,                            // padding
+    ];
+
+    let fde = DebugFrame::new(&debug_frame, 4)
+        .find_fde(0x1020)
+        .unwrap()
+        .unwrap();
+    assert_eq!(fde.cie.address_size, 4);
+    assert_eq!(fde.initial_location, 0x1000);
+    assert_eq!(fde.address_range, 0x100);
+
+    let row = super::process_instructions_cfa(&fde, 0x100f).unwrap();
+    assert_eq!(
+        row.cfa,
+        CfaRule::RegisterOffset {
+            register: 4,
+            offset: 4
+        }
+    );
+    let row = super::process_instructions_cfa(&fde, 0x1010).unwrap();
+    assert_eq!(
+        row.cfa,
+        CfaRule::RegisterOffset {
+            register: 4,
+            offset: 8
+        }
+    );
+
+    // The address of `DW_CFA_set_loc` follows a segment selector.
+    #[rustfmt::skip]
+    let instructions = [
+        0x01, 0xaa, 0xbb, 0x10, 0x10, 0, 0, // DW_CFA_set_loc: 0xbbaa:0x1010
+        0x0e, 0x10,                         // DW_CFA_def_cfa_offset: +16
+    ];
+    let mut fde = simple_fde(&instructions);
+    fde.cie.address_size = 4;
+    fde.cie.segment_selector_size = 2;
+    let row = super::process_instructions_cfa(&fde, FUNCTION + 0x10).unwrap();
+    assert_eq!(
+        row.cfa,
+        CfaRule::RegisterOffset {
+            register: 7,
+            offset: 16
+        }
+    );
+}
@@ -0,0 +1,219 @@
+//! Frame information that is registered at runtime, mostly by JIT compilers
+//! that emit `.eh_frame` for the code they generate. The dynamic linker
+//! doesn't know about that code, so we have to keep track of it ourselves.
+//!
+//! We follow the libgcc interface: a registration is a whole `.eh_frame`
+//! section, a sequence of CIEs and FDEs terminated by a zero length field.
+//!
+//! The registry is behind a spinlock, which lookups hold while they parse the
+//! registered sections, so deregistrations have to wait for them. Like in
+//! libgcc, this means that it must not be used from a signal handler that
+//! may have interrupted a registration or a lookup on the same thread, it
+//! would wait for itself forever. Unwinding from such a signal handler only
+//! gets here for addresses outside of the loaded objects.
+
+#![allow(non_camel_case_types)]
+
+use alloc::boxed::Box;
+use core::{
+    cell::UnsafeCell,
+    ffi,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use super::parse::{EhFrame, ParsedFde};
+
+#[cfg(test)]
+mod tests;
+
+/// A registered `.eh_frame` section. The storage is provided by the caller of
+/// `__register_frame_info`, who sized it for the libgcc `struct object`, which
+/// is larger than this.
+#[repr(C)]
+struct Object {
+    eh_frame: *const u8,
+    /// The length of the section, including the zero terminator.
+    len: usize,
+    next: *mut Object,
+}
+
+/// The size of `struct object` in libgcc, which callers allocate for us.
+const LIBGCC_OBJECT_SIZE: usize = 6 * size_of::<usize>();
+const _: () = assert!(size_of::<Object>() <= LIBGCC_OBJECT_SIZE);
+
+/// A linked list of registered objects behind a spinlock. We can't rely on a
+/// mutex from the OS, and registrations are rare and quick.
+struct Registry {
+    locked: AtomicBool,
+    head: UnsafeCell<*mut Object>,
+}
+
+// SAFETY: The list is only accessed while holding the lock.
+unsafe impl Sync for Registry {}
+
+impl Registry {
+    fn with<R>(&self, f: impl FnOnce(&mut *mut Object) -> R) -> R {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+        // Unlock even if `f` panics, or every later unwind would spin forever.
+        let _unlock = Unlock(&self.locked);
+        // SAFETY: We hold the lock.
+        f(unsafe { &mut *self.head.get() })
+    }
+}
+
+/// Releases the lock of the registry when dropped.
+struct Unlock<'a>(&'a AtomicBool);
+
+impl Drop for Unlock<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+static REGISTRY: Registry = Registry {
+    locked: AtomicBool::new(false),
+    head: UnsafeCell::new(core::ptr::null_mut()),
+};
+
+/// Finds the FDE covering `addr` in the registered frame information.
+///
+/// This deadlocks in a signal handler that interrupted the registry on the
+/// same thread, see the module docs.
+pub(crate) fn frame_info(addr: usize) -> Option<ParsedFde<'static>> {
+    REGISTRY.with(|head| {
+        let mut object = *head;
+        while !object.is_null() {
+            // SAFETY: Registered sections stay valid until they are
+            // deregistered, which can't happen while we hold the lock.
+            unsafe {
+                let bytes = core::slice::from_raw_parts((*object).eh_frame, (*object).len);
+                match EhFrame::in_process(bytes).find_fde(addr) {
+                    Ok(Some(fde)) => return Some(fde),
+                    Ok(None) => {}
+                    // One broken registration must not hide the others.
+                    Err(err) => warn!(
+                        ?err,
+                        "skipping invalid frame information at {:p}",
+                        (*object).eh_frame
+                    ),
+                }
+                object = (*object).next;
+            }
+        }
+        None
+    })
+}
+
+/// Finds the length of the `.eh_frame` section at `begin` by walking the
+/// lengths of its entries up to the zero terminator.
+///
+/// # Safety
+/// `begin` must point to a valid `.eh_frame` section.
+unsafe fn eh_frame_len(begin: *const u8) -> usize {
+    let mut len = 0;
+    loop {
+        let entry = begin.add(len);
+        match entry.cast::<u32>().read_unaligned() {
+            0 => return len + 4,
+            // An initial length of 0xffffffff is followed by the real 64-bit
+            // length.
+            0xffffffff => len += 12 + entry.add(4).cast::<u64>().read_unaligned() as usize,
+            entry_len => len += 4 + entry_len as usize,
+        }
+    }
+}
+
+/// Registers the `.eh_frame` section at `begin`, using `ob` as storage for the
+/// bookkeeping. This is what `crtbegin.o` calls for binaries without
+/// `.eh_frame_hdr`.
+///
+/// # Safety
+/// `begin` must point to a valid `.eh_frame` section that stays alive until it
+/// is deregistered. `ob` must be valid for writes of the size of libgcc's
+/// `struct object` (six words) and stay alive as long as well.
+#[no_mangle]
+pub unsafe extern "C" fn __register_frame_info(begin: *const ffi::c_void, ob: *mut ffi::c_void) {
+    // An empty section has nothing to register.
+    if begin.is_null() || begin.cast::<u32>().read_unaligned() == 0 {
+        return;
+    }
+    trace!("registering frame information at {begin:p}");
+
+    let object = ob.cast::<Object>();
+    let len = eh_frame_len(begin.cast());
+    REGISTRY.with(|head| {
+        object.write(Object {
+            eh_frame: begin.cast(),
+            len,
+            next: *head,
+        });
+        *head = object;
+    });
+}
+
+/// Deregisters the `.eh_frame` section at `begin`, returning the storage that
+/// was passed to `__register_frame_info`, or null if it isn't registered.
+///
+/// # Safety
+/// Must not be called while another thread is unwinding through the code
+/// covered by the section.
+#[no_mangle]
+pub unsafe extern "C" fn __deregister_frame_info(begin: *const ffi::c_void) -> *mut ffi::c_void {
+    if begin.is_null() || begin.cast::<u32>().read_unaligned() == 0 {
+        return core::ptr::null_mut();
+    }
+    trace!("deregistering frame information at {begin:p}");
+
+    REGISTRY.with(|head| {
+        let mut link: *mut *mut Object = head;
+        while !(*link).is_null() {
+            let object = *link;
+            if (*object).eh_frame == begin.cast() {
+                *link = (*object).next;
+                return object.cast();
+            }
+            link = &raw mut (*object).next;
+        }
+
+        warn!("tried to deregister unknown frame information at {begin:p}");
+        core::ptr::null_mut()
+    })
+}
+
+/// Registers the `.eh_frame` section at `begin`. This is how JIT compilers tell
+/// us about the code they generated.
+///
+/// # Safety
+/// `begin` must point to a valid `.eh_frame` section that stays alive until it
+/// is deregistered.
+#[no_mangle]
+pub unsafe extern "C" fn __register_frame(begin: *const ffi::c_void) {
+    if begin.is_null() || begin.cast::<u32>().read_unaligned() == 0 {
+        return;
+    }
+
+    let ob = Box::into_raw(Box::new([0usize; LIBGCC_OBJECT_SIZE / size_of::<usize>()]));
+    __register_frame_info(begin, ob.cast());
+}
+
+/// Deregisters the `.eh_frame` section at `begin` that was registered with
+/// [`__register_frame`].
+///
+/// # Safety
+/// Must not be called while another thread is unwinding through the code
+/// covered by the section.
+#[no_mangle]
+pub unsafe extern "C" fn __deregister_frame(begin: *const ffi::c_void) {
+    let ob = __deregister_frame_info(begin);
+    if !ob.is_null() {
+        drop(Box::from_raw(
+            ob.cast::<[usize; LIBGCC_OBJECT_SIZE / size_of::<usize>()]>(),
+        ));
+    }
+}
@@ -0,0 +1,60 @@
+use core::ffi;
+
+use crate::{
+    dwarf::{frame_info, parse::tests::CfiBuilder},
+    Addr,
+};
+
+#[test]
+fn register_and_deregister() {
+    let eh_frame = CfiBuilder::default().build(&[(0x10000, 0x100)]);
+    let addr = Addr(core::ptr::without_provenance(0x10010));
+
+    assert!(frame_info(addr).unwrap().is_none());
+
+    unsafe { super::__register_frame(eh_frame.as_ptr().cast::<ffi::c_void>()) };
+    let fde = frame_info(addr).unwrap().unwrap();
+    assert_eq!(fde.initial_location, 0x10000);
+    assert_eq!(fde.address_range, 0x100);
+    assert!(frame_info(Addr(core::ptr::without_provenance(0x10100)))
+        .unwrap()
+        .is_none());
+
+    unsafe { super::__deregister_frame(eh_frame.as_ptr().cast::<ffi::c_void>()) };
+    assert!(frame_info(addr).unwrap().is_none());
+}
+
+#[test]
+fn skip_invalid_registration() {
+    let good = CfiBuilder::default().build(&[(0x20000, 0x100)]);
+    let mut bad = CfiBuilder::default().build(&[(0x30000, 0x100)]);
+    bad[8] = 9; // CIE version
+    let addr = Addr(core::ptr::without_provenance(0x20010));
+
+    unsafe {
+        super::__register_frame(good.as_ptr().cast::<ffi::c_void>());
+        super::__register_frame(bad.as_ptr().cast::<ffi::c_void>());
+    }
+    // The broken section is searched first.
+    let fde = frame_info(addr).unwrap().unwrap();
+    assert_eq!(fde.initial_location, 0x20000);
+
+    unsafe {
+        super::__deregister_frame(bad.as_ptr().cast::<ffi::c_void>());
+        super::__deregister_frame(good.as_ptr().cast::<ffi::c_void>());
+    }
+}
+
+#[test]
+fn lock_released_on_panic() {
+    let registry = super::Registry {
+        locked: false.into(),
+        head: core::ptr::null_mut::<super::Object>().into(),
+    };
+
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        registry.with(|_| panic!("while locked"))
+    }));
+    assert!(result.is_err());
+    assert!(registry.with(|head| head.is_null()));
+}
@@ -6,10 +6,11 @@ extern crate alloc;
 #[macro_use]
 extern crate tracing;
 
-use core::{ffi, sync::atomic::AtomicPtr};
+use core::ffi;
 
 mod stdext;
 
+mod backtrace;
 pub mod uw;
 
 mod arch;
@@ -18,6 +19,11 @@ mod identify;
 
 mod walk;
 
+#[cfg(test)]
+mod tests;
+
+pub use backtrace::{Backtrace, Frame, Frames};
+
 #[derive(Debug, Clone, Copy)]
 struct Addr(*const ());
 
@@ -31,17 +37,483 @@ impl Addr {
     }
 }
 
-#[allow(nonstandard_style)]
-pub unsafe extern "C-unwind" fn _UnwindRaiseException(
+/// Looks up the unwind information for the frame with the registers `regs`.
+/// Returns `None` at the end of the stack, or if there is no unwind
+/// information for the frame.
+///
+/// `signal_frame` is set if the frame was interrupted by a signal, so that its
+/// IP is the interrupted instruction instead of a return address.
+fn frame(
+    regs: arch::Context,
+    signal_frame: bool,
+) -> dwarf::parse::Result<Option<uw::_Unwind_Context>> {
+    let ip = regs.registers[dwarf::parse::arch::RETURN_ADDRESS];
+    if ip == 0 {
+        return Ok(None);
+    }
+
+    let pc = uw::_Unwind_Context::lookup_address(ip, signal_frame);
+    let Some(fde) = dwarf::frame_info(Addr(core::ptr::with_exposed_provenance(pc)))? else {
+        return Ok(None);
+    };
+    let args_size = dwarf::parse::process_instructions_cfa(&fde, pc)?.args_size;
+
+    Ok(Some(uw::_Unwind_Context {
+        regs,
+        fde,
+        signal_frame,
+        args_size,
+    }))
+}
+
+/// Unwinds a frame, returning the registers of its caller. The stack pointer
+/// of the caller is the CFA of the frame.
+fn caller_regs(ctx: &uw::_Unwind_Context) -> dwarf::parse::Result<arch::Context> {
+    let pc = uw::_Unwind_Context::lookup_address(
+        ctx.regs.registers[dwarf::parse::arch::RETURN_ADDRESS],
+        ctx.signal_frame,
+    );
+
+    let row = dwarf::parse::process_instructions_cfa(&ctx.fde, pc)?;
+    // SAFETY: We trust the CFI to only point us to valid stack slots.
+    let memory = unsafe { dwarf::expr::ProcessMemory::new() };
+    dwarf::parse::step(&row, &ctx.fde.cie, &ctx.regs, &memory)
+}
+
+/// Unwinds a frame, returning the frame of its caller or `None` at the end of
+/// the stack.
+fn caller(ctx: &uw::_Unwind_Context) -> dwarf::parse::Result<Option<uw::_Unwind_Context>> {
+    frame(caller_regs(ctx)?, ctx.fde.cie.is_signal_frame())
+}
+
+/// Returns the frame of the caller of the function that captured `regs`.
+fn caller_of_captured(regs: arch::Context) -> dwarf::parse::Result<Option<uw::_Unwind_Context>> {
+    match frame(regs, false)? {
+        Some(ctx) => caller(&ctx),
+        None => Ok(None),
+    }
+}
+
+/// Turns an error from the unwind information into `reason`, the fatal error
+/// of the current phase. There is nothing better to do with it than to tell
+/// the caller that unwinding failed.
+fn fatal<T>(
+    result: dwarf::parse::Result<T>,
+    reason: uw::_Unwind_Reason_Code,
+) -> Result<T, uw::_Unwind_Reason_Code> {
+    result.map_err(|err| {
+        warn!(?err, "failed to unwind frame");
+        reason
+    })
+}
+
+fn personality(ctx: &uw::_Unwind_Context) -> Option<uw::PersonalityRoutine> {
+    let personality = ctx.fde.cie.augmentation?.personality?;
+    // SAFETY: The CIE is loaded in our process, and it says this is the
+    // personality routine, so we have to trust it.
+    Some(unsafe { core::mem::transmute::<usize, uw::PersonalityRoutine>(personality.resolve()) })
+}
+
+fn stack_pointer(ctx: &uw::_Unwind_Context) -> usize {
+    ctx.regs.registers[dwarf::parse::arch::REG_STACK_POINTER]
+}
+
+/// Resumes execution at the landing pad that the personality routine set up
+/// in the frame of `ctx`.
+unsafe fn install_context(ctx: &uw::_Unwind_Context) -> ! {
+    debug!(
+        "installing context at ip={:x}",
+        ctx.regs.registers[dwarf::parse::arch::RETURN_ADDRESS]
+    );
+    let mut regs = ctx.regs.clone();
+    // Like libgcc, we pop the arguments of the call the landing pad is for.
+    let sp = &mut regs.registers[dwarf::parse::arch::REG_STACK_POINTER];
+    *sp = sp.wrapping_add(ctx.args_size);
+    arch::restore_context(&regs)
+}
+
+/// Phase 1 of raising: walk the stack without modifying anything and ask every
+/// personality routine whether its frame handles the exception.
+/// Returns the stack pointer of the handler frame.
+unsafe fn search_phase(
+    exception_object: *mut uw::_Unwind_Exception,
+    mut ctx: uw::_Unwind_Context,
+) -> Result<usize, uw::_Unwind_Reason_Code> {
+    loop {
+        let sp = stack_pointer(&ctx);
+        let _span = debug_span!("search_phase", sp = format_args!("{sp:x}")).entered();
+
+        if let Some(personality) = personality(&ctx) {
+            let reason = personality(
+                1,
+                uw::_UA_SEARCH_PHASE,
+                (*exception_object).exception_class,
+                exception_object,
+                &mut ctx,
+            );
+            trace!(?reason, "personality returned");
+
+            match reason {
+                uw::_Unwind_Reason_Code::_URC_CONTINUE_UNWIND => {}
+                uw::_Unwind_Reason_Code::_URC_HANDLER_FOUND => return Ok(sp),
+                _ => return Err(uw::_Unwind_Reason_Code::_URC_FATAL_PHASE1_ERROR),
+            }
+        }
+
+        ctx = fatal(
+            caller(&ctx),
+            uw::_Unwind_Reason_Code::_URC_FATAL_PHASE1_ERROR,
+        )?
+        .ok_or(uw::_Unwind_Reason_Code::_URC_END_OF_STACK)?;
+    }
+}
+
+/// Phase 2 of raising: walk the stack again, running the cleanups of every
+/// frame until we reach the handler frame found in phase 1.
+/// Only returns if something went wrong.
+unsafe fn cleanup_phase(
+    exception_object: *mut uw::_Unwind_Exception,
+    mut ctx: uw::_Unwind_Context,
+) -> uw::_Unwind_Reason_Code {
+    let handler_sp = (*exception_object).private_2;
+
+    loop {
+        let sp = stack_pointer(&ctx);
+        let _span = debug_span!("cleanup_phase", sp = format_args!("{sp:x}")).entered();
+
+        let is_handler_frame = sp == handler_sp;
+        let mut actions = uw::_UA_CLEANUP_PHASE;
+        if is_handler_frame {
+            actions |= uw::_UA_HANDLER_FRAME;
+        }
+
+        if let Some(personality) = personality(&ctx) {
+            let reason = personality(
+                1,
+                actions,
+                (*exception_object).exception_class,
+                exception_object,
+                &mut ctx,
+            );
+            trace!(?reason, "personality returned");
+
+            match reason {
+                uw::_Unwind_Reason_Code::_URC_CONTINUE_UNWIND => {}
+                uw::_Unwind_Reason_Code::_URC_INSTALL_CONTEXT => install_context(&ctx),
+                _ => return uw::_Unwind_Reason_Code::_URC_FATAL_PHASE2_ERROR,
+            }
+        }
+
+        if is_handler_frame {
+            // The handler frame told us in phase 1 that it would catch the
+            // exception, it must not change its mind now.
+            return uw::_Unwind_Reason_Code::_URC_FATAL_PHASE2_ERROR;
+        }
+
+        let Ok(Some(next)) = fatal(
+            caller(&ctx),
+            uw::_Unwind_Reason_Code::_URC_FATAL_PHASE2_ERROR,
+        ) else {
+            return uw::_Unwind_Reason_Code::_URC_FATAL_PHASE2_ERROR;
+        };
+        ctx = next;
+    }
+}
+
+/// Phase 2 of a forced unwind: there is no handler frame, instead the stop
+/// function decides where to stop. Every frame runs its cleanups.
+///
+/// `ctx` is the frame of the unwinder function that started or continues the
+/// forced unwind, the unwinding starts at its caller. Like libgcc, this calls
+/// the stop function one last time with `_UA_END_OF_STACK` past the outermost
+/// frame we can unwind, and returns `_URC_END_OF_STACK` if it lets us go on.
+/// Otherwise, this only returns if something went wrong.
+unsafe fn forced_unwind_phase(
+    exception_object: *mut uw::_Unwind_Exception,
+    mut ctx: uw::_Unwind_Context,
+) -> uw::_Unwind_Reason_Code {
+    // SAFETY: `_Unwind_ForcedUnwind` stored the stop function here.
+    let stop = core::mem::transmute::<usize, uw::_Unwind_Stop_Fn>((*exception_object).private_1);
+    let stop_parameter =
+        core::ptr::with_exposed_provenance_mut::<ffi::c_void>((*exception_object).private_2);
+    let actions = uw::_UA_FORCE_UNWIND | uw::_UA_CLEANUP_PHASE;
+
+    loop {
+        let regs = match fatal(
+            caller_regs(&ctx),
+            uw::_Unwind_Reason_Code::_URC_FATAL_PHASE2_ERROR,
+        ) {
+            Ok(regs) => regs,
+            Err(reason) => return reason,
+        };
+        let signal_frame = ctx.fde.cie.is_signal_frame();
+
+        let next = match fatal(
+            frame(regs.clone(), signal_frame),
+            uw::_Unwind_Reason_Code::_URC_FATAL_PHASE2_ERROR,
+        ) {
+            Ok(next) => next,
+            Err(reason) => return reason,
+        };
+        let Some(next) = next else {
+            // We are past the outermost frame. Like in libgcc, the context
+            // still has the bases of the last frame, but no LSDA.
+            let mut end = uw::_Unwind_Context {
+                regs,
+                fde: dwarf::ParsedFde {
+                    lsda: None,
+                    ..ctx.fde
+                },
+                signal_frame,
+                args_size: 0,
+            };
+            let reason = stop(
+                1,
+                actions | uw::_UA_END_OF_STACK,
+                (*exception_object).exception_class,
+                exception_object,
+                &mut end,
+                stop_parameter,
+            );
+            trace!(?reason, "stop function returned at the end of the stack");
+            if reason != uw::_Unwind_Reason_Code::_URC_NO_REASON {
+                return uw::_Unwind_Reason_Code::_URC_FATAL_PHASE2_ERROR;
+            }
+            return uw::_Unwind_Reason_Code::_URC_END_OF_STACK;
+        };
+        ctx = next;
+
+        let sp = stack_pointer(&ctx);
+        let _span = debug_span!("forced_unwind_phase", sp = format_args!("{sp:x}")).entered();
+
+        let reason = stop(
+            1,
+            actions,
+            (*exception_object).exception_class,
+            exception_object,
+            &mut ctx,
+            stop_parameter,
+        );
+        trace!(?reason, "stop function returned");
+        if reason != uw::_Unwind_Reason_Code::_URC_NO_REASON {
+            return uw::_Unwind_Reason_Code::_URC_FATAL_PHASE2_ERROR;
+        }
+
+        if let Some(personality) = personality(&ctx) {
+            let reason = personality(
+                1,
+                actions,
+                (*exception_object).exception_class,
+                exception_object,
+                &mut ctx,
+            );
+            trace!(?reason, "personality returned");
+
+            match reason {
+                uw::_Unwind_Reason_Code::_URC_CONTINUE_UNWIND => {}
+                uw::_Unwind_Reason_Code::_URC_INSTALL_CONTEXT => install_context(&ctx),
+                _ => return uw::_Unwind_Reason_Code::_URC_FATAL_PHASE2_ERROR,
+            }
+        }
+    }
+}
+
+/// Runs a forced unwind, starting at the caller of the function that captured
+/// `regs`.
+unsafe fn forced_unwind_from_captured(
+    exception_object: *mut uw::_Unwind_Exception,
+    regs: arch::Context,
+) -> uw::_Unwind_Reason_Code {
+    match fatal(
+        frame(regs, false),
+        uw::_Unwind_Reason_Code::_URC_FATAL_PHASE2_ERROR,
+    ) {
+        Ok(Some(ctx)) => forced_unwind_phase(exception_object, ctx),
+        // We always have unwind information for our own code.
+        Ok(None) => uw::_Unwind_Reason_Code::_URC_FATAL_PHASE2_ERROR,
+        Err(reason) => reason,
+    }
+}
+
+/// Raises an exception. This first searches for a handler (phase 1), and then
+/// unwinds the stack up to that handler, running cleanups along the way
+/// (phase 2).
+///
+/// This only returns if no handler was found (`_URC_END_OF_STACK`) or if
+/// something went wrong.
+///
+/// # Safety
+/// `exception_object` must point to a valid exception that stays alive until
+/// it has been caught.
+#[no_mangle]
+#[inline(never)]
+pub unsafe extern "C-unwind" fn _Unwind_RaiseException(
     exception_object: *mut uw::_Unwind_Exception,
 ) -> uw::_Unwind_Reason_Code {
-    let _span = info_span!("_UnwindRaiseException", ?exception_object).entered();
+    let _span = info_span!("_Unwind_RaiseException", ?exception_object).entered();
 
-    let ctx = arch::capture_context();
+    let mut regs = arch::Context::new();
+    arch::capture_context(&mut regs);
 
-    let frame = crate::dwarf::frame_info(Addr(core::ptr::with_exposed_provenance(
-        ctx.registers[dwarf::parse::arch::RETURN_ADDRESS],
-    )));
+    // The captured context is our own frame, which is why none of the entry
+    // points may be inlined. The caller is the first frame that may be
+    // interested in the exception.
+    let start = match fatal(
+        caller_of_captured(regs),
+        uw::_Unwind_Reason_Code::_URC_FATAL_PHASE1_ERROR,
+    ) {
+        Ok(Some(start)) => start,
+        Ok(None) => return uw::_Unwind_Reason_Code::_URC_END_OF_STACK,
+        Err(reason) => return reason,
+    };
 
+    let handler_sp = match search_phase(exception_object, start.clone()) {
+        Ok(sp) => sp,
+        Err(reason) => {
+            debug!(?reason, "search phase did not find a handler");
+            return reason;
+        }
+    };
+    debug!("found handler frame with sp={handler_sp:x}");
+
+    (*exception_object).private_1 = 0;
+    (*exception_object).private_2 = handler_sp;
+
+    cleanup_phase(exception_object, start)
+}
+
+/// Continues unwinding after a cleanup landing pad has run. Landing pads that
+/// don't catch the exception call this at their end.
+///
+/// The state of phase 2 is stored in the exception object, so we just walk on
+/// from the frame of the landing pad.
+///
+/// # Safety
+/// `exception_object` must be the exception that is currently being unwound
+/// and was passed to the landing pad.
+#[no_mangle]
+#[inline(never)]
+pub unsafe extern "C-unwind" fn _Unwind_Resume(exception_object: *mut uw::_Unwind_Exception) -> ! {
+    let _span = info_span!("_Unwind_Resume", ?exception_object).entered();
+
+    let mut regs = arch::Context::new();
+    arch::capture_context(&mut regs);
+
+    // The caller is the frame of the landing pad. Its personality routine will
+    // not find anything to do at the call to us and continue unwinding.
+    let reason = if (*exception_object).private_1 != 0 {
+        // A stop function means that this is a forced unwind.
+        forced_unwind_from_captured(exception_object, regs)
+    } else {
+        match fatal(
+            caller_of_captured(regs),
+            uw::_Unwind_Reason_Code::_URC_FATAL_PHASE2_ERROR,
+        ) {
+            Ok(Some(start)) => cleanup_phase(exception_object, start),
+            Ok(None) => uw::_Unwind_Reason_Code::_URC_FATAL_PHASE2_ERROR,
+            Err(reason) => reason,
+        }
+    };
+
+    // There is nobody we could return an error to.
+    error!(?reason, "failed to resume unwinding");
     stdext::abort();
 }
+
+/// Unwinds the stack without searching for a handler, running the cleanups of
+/// every frame. This is used by `pthread_exit` and `pthread_cancel`.
+///
+/// `stop` is called for every frame and decides when to stop unwinding. It is
+/// called once more with `_UA_END_OF_STACK` past the outermost frame, and is
+/// responsible for doing something sensible there.
+///
+/// This returns `_URC_END_OF_STACK` if `stop` didn't stop at the end of the
+/// stack, and only returns otherwise if something went wrong.
+///
+/// # Safety
+/// `exception_object` must point to a valid exception that stays alive until
+/// the unwinding is done, and `stop` must be safe to call with `stop_parameter`.
+#[no_mangle]
+#[inline(never)]
+pub unsafe extern "C-unwind" fn _Unwind_ForcedUnwind(
+    exception_object: *mut uw::_Unwind_Exception,
+    stop: uw::_Unwind_Stop_Fn,
+    stop_parameter: *mut ffi::c_void,
+) -> uw::_Unwind_Reason_Code {
+    let _span = info_span!("_Unwind_ForcedUnwind", ?exception_object).entered();
+
+    let mut regs = arch::Context::new();
+    arch::capture_context(&mut regs);
+
+    (*exception_object).private_1 = stop as usize;
+    (*exception_object).private_2 = stop_parameter.expose_provenance();
+
+    forced_unwind_from_captured(exception_object, regs)
+}
+
+/// Walks the stack, calling `trace` with the context of every frame, starting
+/// with the caller of this function.
+///
+/// Returns `_URC_END_OF_STACK` after the outermost frame. If `trace` stops the
+/// walk early, it returns `_URC_FATAL_PHASE1_ERROR` like libgcc does.
+///
+/// # Safety
+/// `trace` must be safe to call with `arg`.
+#[no_mangle]
+#[inline(never)]
+pub unsafe extern "C-unwind" fn _Unwind_Backtrace(
+    trace: uw::_Unwind_Trace_Fn,
+    arg: *mut ffi::c_void,
+) -> uw::_Unwind_Reason_Code {
+    let _span = info_span!("_Unwind_Backtrace").entered();
+
+    let mut regs = arch::Context::new();
+    arch::capture_context(&mut regs);
+
+    let phase1_error = uw::_Unwind_Reason_Code::_URC_FATAL_PHASE1_ERROR;
+    let mut next = match fatal(caller_of_captured(regs), phase1_error) {
+        Ok(next) => next,
+        Err(reason) => return reason,
+    };
+    while let Some(mut ctx) = next {
+        let reason = trace(&mut ctx, arg);
+        if reason != uw::_Unwind_Reason_Code::_URC_NO_REASON {
+            trace!(?reason, "trace function stopped the backtrace");
+            return phase1_error;
+        }
+        next = match fatal(caller(&ctx), phase1_error) {
+            Ok(next) => next,
+            Err(reason) => return reason,
+        };
+    }
+
+    uw::_Unwind_Reason_Code::_URC_END_OF_STACK
+}
+
+/// Rethrows an exception that was caught. If the exception came from a forced
+/// unwind, the forced unwind continues instead, it can't be caught.
+///
+/// This only returns if something went wrong, see [`_Unwind_RaiseException`].
+///
+/// # Safety
+/// `exception_object` must point to a valid exception that was caught by the
+/// caller and stays alive until it has been caught again.
+#[no_mangle]
+#[inline(never)]
+pub unsafe extern "C-unwind" fn _Unwind_Resume_or_Rethrow(
+    exception_object: *mut uw::_Unwind_Exception,
+) -> uw::_Unwind_Reason_Code {
+    let _span = info_span!("_Unwind_Resume_or_Rethrow", ?exception_object).entered();
+
+    // Without a stop function, this is a normal exception that starts over
+    // with a new search phase. Our own frame doesn't have a personality
+    // routine, so it's skipped.
+    if (*exception_object).private_1 == 0 {
+        return _Unwind_RaiseException(exception_object);
+    }
+
+    let mut regs = arch::Context::new();
+    arch::capture_context(&mut regs);
+
+    forced_unwind_from_captured(exception_object, regs)
+}
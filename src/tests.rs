@@ -0,0 +1,279 @@
+use core::{cell::Cell, ffi};
+
+use crate::{uw, Backtrace};
+
+struct Trace {
+    region_starts: [usize; 64],
+    len: usize,
+    limit: usize,
+    /// Whether a forced unwind told the stop function that it reached the end
+    /// of the stack.
+    end_of_stack: bool,
+}
+
+impl Trace {
+    fn new(limit: usize) -> Self {
+        Trace {
+            region_starts: [0; 64],
+            len: 0,
+            limit,
+            end_of_stack: false,
+        }
+    }
+}
+
+unsafe extern "C" fn record_frame(
+    context: *mut uw::_Unwind_Context,
+    arg: *mut ffi::c_void,
+) -> uw::_Unwind_Reason_Code {
+    let trace = &mut *arg.cast::<Trace>();
+    if trace.len == trace.limit {
+        return uw::_Unwind_Reason_Code::_URC_NORMAL_STOP;
+    }
+    trace.region_starts[trace.len] = uw::_Unwind_GetRegionStart(context);
+    trace.len += 1;
+    uw::_Unwind_Reason_Code::_URC_NO_REASON
+}
+
+#[inline(never)]
+fn backtrace_from_here(limit: usize) -> (uw::_Unwind_Reason_Code, Trace) {
+    let mut trace = Trace::new(limit);
+    let reason =
+        unsafe { crate::_Unwind_Backtrace(record_frame, (&raw mut trace).cast::<ffi::c_void>()) };
+    (reason, trace)
+}
+
+#[test]
+fn backtrace() {
+    let (reason, trace) = backtrace_from_here(64);
+    assert_eq!(reason, uw::_Unwind_Reason_Code::_URC_END_OF_STACK);
+    // This test, the test harness and the thread start.
+    assert!(trace.len > 3, "only {} frames", trace.len);
+    assert_eq!(
+        trace.region_starts[0],
+        (backtrace_from_here as *const ()).addr()
+    );
+}
+
+#[test]
+fn backtrace_stops_early() {
+    let (reason, trace) = backtrace_from_here(2);
+    assert_eq!(reason, uw::_Unwind_Reason_Code::_URC_FATAL_PHASE1_ERROR);
+    assert_eq!(trace.len, 2);
+}
+
+#[inline(never)]
+fn capture_from_here() -> Backtrace {
+    let backtrace = Backtrace::capture();
+    // Keep our frame, the capture must not become a tail call.
+    core::hint::black_box(&backtrace);
+    backtrace
+}
+
+#[test]
+fn safe_backtrace() {
+    let backtrace = capture_from_here();
+    assert!(!backtrace.is_truncated());
+
+    let frames = backtrace.frames().collect::<Vec<_>>();
+    assert!(frames.len() > 3, "only {} frames", frames.len());
+    assert_eq!(
+        frames[0].symbol_address,
+        (capture_from_here as *const ()).addr()
+    );
+
+    for pair in frames.windows(2) {
+        assert!(pair[0].sp < pair[0].cfa);
+        assert_eq!(pair[0].cfa, pair[1].sp);
+    }
+}
+
+unsafe extern "C" fn record_stop(
+    _version: ffi::c_int,
+    actions: uw::_UnwindAction,
+    _exception_class: u64,
+    _exception_object: *mut uw::_Unwind_Exception,
+    context: *mut uw::_Unwind_Context,
+    stop_parameter: *mut ffi::c_void,
+) -> uw::_Unwind_Reason_Code {
+    let trace = &mut *stop_parameter.cast::<Trace>();
+    assert!(!trace.end_of_stack);
+    if actions & uw::_UA_END_OF_STACK != 0 {
+        trace.end_of_stack = true;
+        return uw::_Unwind_Reason_Code::_URC_NO_REASON;
+    }
+    assert_eq!(actions, uw::_UA_FORCE_UNWIND | uw::_UA_CLEANUP_PHASE);
+    if trace.len == trace.limit {
+        return uw::_Unwind_Reason_Code::_URC_NORMAL_STOP;
+    }
+    trace.region_starts[trace.len] = uw::_Unwind_GetRegionStart(context);
+    trace.len += 1;
+    uw::_Unwind_Reason_Code::_URC_NO_REASON
+}
+
+fn forced_exception() -> uw::_Unwind_Exception {
+    uw::_Unwind_Exception {
+        exception_class: u64::from_be_bytes(*b"uwuwTEST"),
+        exception_cleanup: None,
+        private_1: 0,
+        private_2: 0,
+    }
+}
+
+/// Forces an unwind that the stop function ends after `limit` frames. The
+/// frames in between must not have cleanups, or we would run them.
+#[inline(never)]
+fn forced_unwind_from_here(limit: usize) -> (uw::_Unwind_Reason_Code, Trace) {
+    let mut trace = Trace::new(limit);
+    let mut exception = forced_exception();
+    let reason = unsafe {
+        crate::_Unwind_ForcedUnwind(
+            &mut exception,
+            record_stop,
+            (&raw mut trace).cast::<ffi::c_void>(),
+        )
+    };
+    (reason, trace)
+}
+
+#[test]
+fn forced_unwind_stops() {
+    // Stopping is an error, the stop function should have jumped away instead.
+    let (reason, trace) = forced_unwind_from_here(0);
+    assert_eq!(reason, uw::_Unwind_Reason_Code::_URC_FATAL_PHASE2_ERROR);
+    assert_eq!(trace.len, 0);
+
+    let (reason, trace) = forced_unwind_from_here(2);
+    assert_eq!(reason, uw::_Unwind_Reason_Code::_URC_FATAL_PHASE2_ERROR);
+    assert_eq!(trace.len, 2);
+    assert_eq!(
+        trace.region_starts[0],
+        (forced_unwind_from_here as *const ()).addr()
+    );
+    assert_eq!(
+        trace.region_starts[1],
+        (forced_unwind_stops as *const ()).addr()
+    );
+}
+
+#[test]
+fn forced_unwind_to_end_of_stack() {
+    // A thread of our own has no frames with cleanups that we would run.
+    extern "C-unwind" fn start(result: *mut ffi::c_void) -> *mut ffi::c_void {
+        unsafe {
+            result
+                .cast::<(uw::_Unwind_Reason_Code, Trace)>()
+                .write(forced_unwind_from_here(64))
+        };
+        core::ptr::null_mut()
+    }
+
+    let mut result = core::mem::MaybeUninit::<(uw::_Unwind_Reason_Code, Trace)>::uninit();
+    unsafe {
+        let mut thread = core::mem::zeroed();
+        let start = core::mem::transmute::<
+            extern "C-unwind" fn(*mut ffi::c_void) -> *mut ffi::c_void,
+            extern "C" fn(*mut ffi::c_void) -> *mut ffi::c_void,
+        >(start);
+        let created = libc::pthread_create(
+            &mut thread,
+            core::ptr::null(),
+            start,
+            result.as_mut_ptr().cast::<ffi::c_void>(),
+        );
+        assert_eq!(created, 0);
+        assert_eq!(libc::pthread_join(thread, core::ptr::null_mut()), 0);
+    }
+    let (reason, trace) = unsafe { result.assume_init() };
+
+    assert_eq!(reason, uw::_Unwind_Reason_Code::_URC_END_OF_STACK);
+    assert!(trace.end_of_stack);
+    // Our two functions and the thread start.
+    assert!(trace.len >= 3, "only {} frames", trace.len);
+    assert_eq!(
+        trace.region_starts[0],
+        (forced_unwind_from_here as *const ()).addr()
+    );
+}
+
+#[inline(never)]
+fn rethrow_from_here() -> (uw::_Unwind_Reason_Code, Trace) {
+    let mut trace = Trace::new(1);
+    // A caught exception of a forced unwind remembers its stop function.
+    let mut exception = uw::_Unwind_Exception {
+        private_1: (record_stop as *const ()).addr(),
+        private_2: (&raw mut trace).expose_provenance(),
+        ..forced_exception()
+    };
+    let reason = unsafe { crate::_Unwind_Resume_or_Rethrow(&mut exception) };
+    (reason, trace)
+}
+
+#[test]
+fn rethrow_continues_forced_unwind() {
+    let (reason, trace) = rethrow_from_here();
+    assert_eq!(reason, uw::_Unwind_Reason_Code::_URC_FATAL_PHASE2_ERROR);
+    assert_eq!(trace.len, 1);
+    assert_eq!(
+        trace.region_starts[0],
+        (rethrow_from_here as *const ()).addr()
+    );
+}
+
+struct Guard<'a>(&'a Cell<bool>);
+
+impl Drop for Guard<'_> {
+    fn drop(&mut self) {
+        self.0.set(true);
+    }
+}
+
+#[inline(never)]
+fn panic_with_guard(dropped: &Cell<bool>) {
+    let _guard = Guard(dropped);
+    // Unwinds like a panic, without the panic hook printing anything.
+    std::panic::resume_unwind(Box::new(42));
+}
+
+#[test]
+fn panic_runs_drop() {
+    let dropped = Cell::new(false);
+    let payload =
+        std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| panic_with_guard(&dropped)))
+            .unwrap_err();
+    assert_eq!(payload.downcast_ref::<i32>(), Some(&42));
+    assert!(dropped.get());
+}
+
+#[test]
+fn exception_layout() {
+    // The layout from the Itanium C++ ABI on x86-64.
+    assert_eq!(size_of::<uw::_Unwind_Exception>(), 32);
+    assert_eq!(align_of::<uw::_Unwind_Exception>(), 16);
+    assert_eq!(
+        core::mem::offset_of!(uw::_Unwind_Exception, exception_cleanup),
+        8
+    );
+    assert_eq!(core::mem::offset_of!(uw::_Unwind_Exception, private_1), 16);
+    assert_eq!(core::mem::offset_of!(uw::_Unwind_Exception, private_2), 24);
+}
+
+#[test]
+fn find_fde() {
+    let function = (backtrace_from_here as *const ())
+        .cast_mut()
+        .cast::<ffi::c_void>();
+    let inside = function.wrapping_byte_add(1);
+
+    assert_eq!(
+        uw::_Unwind_FindEnclosingFunction(inside.wrapping_byte_add(1)),
+        function
+    );
+
+    let mut bases = core::mem::MaybeUninit::uninit();
+    let fde = unsafe { uw::_Unwind_Find_FDE(inside, bases.as_mut_ptr()) };
+    assert!(!fde.is_null());
+    assert_eq!(unsafe { bases.assume_init() }.func, function);
+
+    assert!(uw::_Unwind_FindEnclosingFunction(core::ptr::null_mut()).is_null());
+}
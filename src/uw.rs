@@ -1,5 +1,10 @@
 #![allow(nonstandard_style)] // Closely follow the spec here
 
+use core::ffi;
+
+use crate::{arch::Context, dwarf::parse::ParsedFde};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(C)]
 pub enum _Unwind_Reason_Code {
     _URC_NO_REASON = 0,
@@ -24,34 +29,294 @@ pub enum _Unwind_Reason_Code {
     _URC_CONTINUE_UNWIND = 8,
 }
 
-#[repr(C, align(8))]
+pub type _Unwind_Word = usize;
+pub type _Unwind_Ptr = usize;
+
+/// The header of every exception object. Language runtimes embed this at the
+/// start of their exception type.
+///
+/// `unwind.h` declares it with the largest alignment of the target, which is
+/// 16 bytes on x86-64.
+#[repr(C, align(16))]
 pub struct _Unwind_Exception {
+    /// Identifies the runtime (vendor and language) that raised the exception.
     pub exception_class: u64,
-    pub exception_cleanup0: _Unwind_Exception_Cleanup_Fn,
-    pub private_1: u64,
-    pub private_2: u64,
+    /// Called to destroy the exception object, for example when a foreign
+    /// runtime catches it.
+    pub exception_cleanup: Option<_Unwind_Exception_Cleanup_Fn>,
+    /// Private to the unwinder: the stop function of a forced unwind, or 0.
+    pub private_1: _Unwind_Word,
+    /// Private to the unwinder: the stack pointer of the handler frame, or the
+    /// stop parameter of a forced unwind.
+    pub private_2: _Unwind_Word,
 }
 
 pub type _Unwind_Exception_Cleanup_Fn =
-    fn(reason: _Unwind_Reason_Code, exc: *const _Unwind_Exception);
+    unsafe extern "C" fn(reason: _Unwind_Reason_Code, exc: *mut _Unwind_Exception);
 
 /// The _Unwind_Context type is an opaque type used to refer to a
 /// system-specific data structure used by the system unwinder. This context is
 /// created and destroyed by the system, and passed to the personality routine
 /// during unwinding
-pub struct _Unwind_Context {}
+#[derive(Clone)]
+pub struct _Unwind_Context {
+    /// The registers of the frame. The instruction pointer is the return
+    /// address into this frame.
+    pub(crate) regs: Context,
+    /// The FDE covering the instruction pointer.
+    pub(crate) fde: ParsedFde<'static>,
+    /// Whether the frame was interrupted by a signal. The instruction pointer
+    /// is then the interrupted instruction, not a return address.
+    pub(crate) signal_frame: bool,
+    /// The size of the arguments pushed on the stack at the instruction
+    /// pointer, which we pop when installing a landing pad in the frame.
+    pub(crate) args_size: usize,
+}
 
-pub type PersonalityRoutine = fn(
-    version: i32,
+impl _Unwind_Context {
+    /// The address to look up the unwind information of a frame with the
+    /// instruction pointer `ip` at. A return address may already point to the
+    /// next function if the call was the last instruction of the function.
+    pub(crate) fn lookup_address(ip: usize, signal_frame: bool) -> usize {
+        if signal_frame {
+            ip
+        } else {
+            ip - 1
+        }
+    }
+}
+
+pub type PersonalityRoutine = unsafe extern "C" fn(
+    version: ffi::c_int,
     actions: _UnwindAction,
     exceptionClass: u64,
     exception_object: *mut _Unwind_Exception,
     context: *mut _Unwind_Context,
 ) -> _Unwind_Reason_Code;
 
+/// Called for every frame by `_Unwind_ForcedUnwind` before the personality
+/// routine, with the same arguments and the `stop_parameter` passed to
+/// `_Unwind_ForcedUnwind`. Returning anything other than `_URC_NO_REASON`
+/// aborts the unwinding.
+pub type _Unwind_Stop_Fn = unsafe extern "C" fn(
+    version: ffi::c_int,
+    actions: _UnwindAction,
+    exceptionClass: u64,
+    exception_object: *mut _Unwind_Exception,
+    context: *mut _Unwind_Context,
+    stop_parameter: *mut ffi::c_void,
+) -> _Unwind_Reason_Code;
+
+/// Called by `_Unwind_Backtrace` for every frame. Returning anything other
+/// than `_URC_NO_REASON` stops the backtrace.
+pub type _Unwind_Trace_Fn = unsafe extern "C" fn(
+    context: *mut _Unwind_Context,
+    arg: *mut ffi::c_void,
+) -> _Unwind_Reason_Code;
+
 pub type _UnwindAction = i32;
 
-const _UA_SEARCH_PHASE: _UnwindAction = 1;
-const _UA_CLEANUP_PHASE: _UnwindAction = 2;
-const _UA_HANDLER_FRAME: _UnwindAction = 4;
-const _UA_FORCE_UNWIND: _UnwindAction = 8;
+pub const _UA_SEARCH_PHASE: _UnwindAction = 1;
+pub const _UA_CLEANUP_PHASE: _UnwindAction = 2;
+pub const _UA_HANDLER_FRAME: _UnwindAction = 4;
+pub const _UA_FORCE_UNWIND: _UnwindAction = 8;
+pub const _UA_END_OF_STACK: _UnwindAction = 16;
+
+/// Returns the value of the general register `index`, using the DWARF register
+/// numbering.
+///
+/// # Safety
+/// `context` must be a context passed to a personality routine or callback by
+/// the unwinder.
+#[no_mangle]
+pub unsafe extern "C" fn _Unwind_GetGR(
+    context: *mut _Unwind_Context,
+    index: ffi::c_int,
+) -> _Unwind_Word {
+    match (*context).regs.registers.get(index as usize) {
+        Some(&value) => value,
+        None => {
+            error!("_Unwind_GetGR called with invalid register {index}");
+            crate::stdext::abort();
+        }
+    }
+}
+
+/// Sets the value of the general register `index` for when the context is
+/// installed. This is how the personality routine passes the exception to the
+/// landing pad.
+///
+/// # Safety
+/// `context` must be a context passed to a personality routine or callback by
+/// the unwinder.
+#[no_mangle]
+pub unsafe extern "C" fn _Unwind_SetGR(
+    context: *mut _Unwind_Context,
+    index: ffi::c_int,
+    value: _Unwind_Word,
+) {
+    trace!("_Unwind_SetGR({index}, {value:x})");
+    match (*context).regs.registers.get_mut(index as usize) {
+        Some(register) => *register = value,
+        None => {
+            error!("_Unwind_SetGR called with invalid register {index}");
+            crate::stdext::abort();
+        }
+    }
+}
+
+/// Returns the instruction pointer of the frame. This is the return address,
+/// so it points to the instruction after the call.
+///
+/// # Safety
+/// `context` must be a context passed to a personality routine or callback by
+/// the unwinder.
+#[no_mangle]
+pub unsafe extern "C" fn _Unwind_GetIP(context: *mut _Unwind_Context) -> _Unwind_Ptr {
+    (*context).regs.registers[crate::dwarf::parse::arch::RETURN_ADDRESS]
+}
+
+/// Like [`_Unwind_GetIP`], but also stores whether the instruction pointer
+/// already points at the instruction that was interrupted (instead of after a
+/// call) in `ip_before_insn`.
+///
+/// # Safety
+/// `context` must be a context passed to a personality routine or callback by
+/// the unwinder, `ip_before_insn` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn _Unwind_GetIPInfo(
+    context: *mut _Unwind_Context,
+    ip_before_insn: *mut ffi::c_int,
+) -> _Unwind_Ptr {
+    *ip_before_insn = (*context).signal_frame.into();
+    _Unwind_GetIP(context)
+}
+
+/// Sets the instruction pointer to jump to when the context is installed.
+///
+/// # Safety
+/// `context` must be a context passed to a personality routine or callback by
+/// the unwinder.
+#[no_mangle]
+pub unsafe extern "C" fn _Unwind_SetIP(context: *mut _Unwind_Context, value: _Unwind_Ptr) {
+    trace!("_Unwind_SetIP({value:x})");
+    (*context).regs.registers[crate::dwarf::parse::arch::RETURN_ADDRESS] = value;
+}
+
+/// Returns the canonical frame address of the callee, which is the value of
+/// the stack pointer in this frame. This is what libgcc returns as well, it
+/// uniquely identifies the frame.
+///
+/// # Safety
+/// `context` must be a context passed to a personality routine or callback by
+/// the unwinder.
+#[no_mangle]
+pub unsafe extern "C" fn _Unwind_GetCFA(context: *mut _Unwind_Context) -> _Unwind_Word {
+    (*context).regs.registers[crate::dwarf::parse::arch::REG_STACK_POINTER]
+}
+
+/// Returns the address of the language-specific data area of the frame, or
+/// null if it has none.
+///
+/// # Safety
+/// `context` must be a context passed to a personality routine or callback by
+/// the unwinder.
+#[no_mangle]
+pub unsafe extern "C" fn _Unwind_GetLanguageSpecificData(
+    context: *mut _Unwind_Context,
+) -> *mut ffi::c_void {
+    core::ptr::with_exposed_provenance_mut((*context).fde.lsda.map_or(0, |lsda| lsda.resolve()))
+}
+
+/// Returns the start of the function (the procedure fragment described by the
+/// FDE) of the frame.
+///
+/// # Safety
+/// `context` must be a context passed to a personality routine or callback by
+/// the unwinder.
+#[no_mangle]
+pub unsafe extern "C" fn _Unwind_GetRegionStart(context: *mut _Unwind_Context) -> _Unwind_Ptr {
+    (*context).fde.initial_location
+}
+
+/// Returns the base address for `DW_EH_PE_datarel` values in the module of the
+/// frame.
+///
+/// # Safety
+/// `context` must be a context passed to a personality routine or callback by
+/// the unwinder.
+#[no_mangle]
+pub unsafe extern "C" fn _Unwind_GetDataRelBase(context: *mut _Unwind_Context) -> _Unwind_Ptr {
+    (*context).fde.bases.data
+}
+
+/// Returns the base address for `DW_EH_PE_textrel` values in the module of the
+/// frame.
+///
+/// # Safety
+/// `context` must be a context passed to a personality routine or callback by
+/// the unwinder.
+#[no_mangle]
+pub unsafe extern "C" fn _Unwind_GetTextRelBase(context: *mut _Unwind_Context) -> _Unwind_Ptr {
+    (*context).fde.bases.text
+}
+
+/// Destroys an exception object by calling its cleanup function. Runtimes call
+/// this when they catch an exception they don't own.
+///
+/// # Safety
+/// `exception_object` must point to a valid exception that is not being
+/// unwound anymore.
+#[no_mangle]
+pub unsafe extern "C" fn _Unwind_DeleteException(exception_object: *mut _Unwind_Exception) {
+    if let Some(cleanup) = (*exception_object).exception_cleanup {
+        cleanup(
+            _Unwind_Reason_Code::_URC_FOREIGN_EXCEPTION_CAUGHT,
+            exception_object,
+        );
+    }
+}
+
+/// The base addresses for an FDE returned by [`_Unwind_Find_FDE`].
+#[repr(C)]
+pub struct dwarf_eh_bases {
+    /// The base for `DW_EH_PE_textrel` values.
+    pub tbase: *mut ffi::c_void,
+    /// The base for `DW_EH_PE_datarel` values.
+    pub dbase: *mut ffi::c_void,
+    /// The start of the function covered by the FDE.
+    pub func: *mut ffi::c_void,
+}
+
+/// Finds the FDE covering `pc`, returning a pointer to it and storing its base
+/// addresses in `bases`. Returns null if there is none.
+///
+/// # Safety
+/// `bases` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn _Unwind_Find_FDE(
+    pc: *mut ffi::c_void,
+    bases: *mut dwarf_eh_bases,
+) -> *const ffi::c_void {
+    let Ok(Some(fde)) = crate::dwarf::frame_info(crate::Addr(pc.cast())) else {
+        return core::ptr::null();
+    };
+
+    bases.write(dwarf_eh_bases {
+        tbase: core::ptr::with_exposed_provenance_mut(fde.bases.text),
+        dbase: core::ptr::with_exposed_provenance_mut(fde.bases.data),
+        func: core::ptr::with_exposed_provenance_mut(fde.bases.func),
+    });
+    core::ptr::with_exposed_provenance(fde.address)
+}
+
+/// Returns the start of the function containing the return address `pc`, or
+/// null if it is unknown.
+#[no_mangle]
+pub extern "C" fn _Unwind_FindEnclosingFunction(pc: *mut ffi::c_void) -> *mut ffi::c_void {
+    // `pc` is a return address, which may already be in the next function.
+    match crate::dwarf::frame_info(crate::Addr(pc.wrapping_byte_sub(1).cast())) {
+        Ok(Some(fde)) => core::ptr::with_exposed_provenance_mut(fde.initial_location),
+        Ok(None) | Err(_) => core::ptr::null_mut(),
+    }
+}
@@ -7,6 +7,10 @@ struct Exception {
     uwu: &'static str,
 }
 
+unsafe extern "C" fn cleanup(_reason: uw::_Unwind_Reason_Code, exc: *mut uw::_Unwind_Exception) {
+    drop(Box::from_raw(exc.cast::<Exception>()));
+}
+
 fn main() {
     let registry = tracing_subscriber::Registry::default().with(
         EnvFilter::builder()
@@ -24,13 +28,13 @@ fn main() {
         let exception = Box::into_raw(Box::new(Exception {
             _uwe: uw::_Unwind_Exception {
                 exception_class: 123456,
-                exception_cleanup0: |_, _| {},
+                exception_cleanup: Some(cleanup),
                 private_1: 0,
                 private_2: 0,
             },
             uwu: "meow :3",
         }));
 
-        uwuwind::_UnwindRaiseException(exception.cast::<uw::_Unwind_Exception>());
+        uwuwind::_Unwind_RaiseException(exception.cast::<uw::_Unwind_Exception>());
     }
 }